@@ -3,7 +3,7 @@
 
 use std::path::PathBuf;
 
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 
 use super::config::CONFIG_FILE_DEFAULT;
 
@@ -22,10 +22,20 @@ pub struct Cli {
     pub partitions: Vec<String>,
     #[arg(long = "dry-run")]
     pub dry_run: bool,
+    #[arg(long = "sysroot")]
+    pub sysroot: Option<PathBuf>,
+    #[arg(long = "format", value_enum, default_value_t = OutputFormat::Text)]
+    pub format: OutputFormat,
     #[command(subcommand)]
     pub command: Option<Commands>,
 }
 
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
 #[derive(Subcommand, Debug)]
 pub enum Commands {
     GenConfig {
@@ -49,6 +59,8 @@ pub enum Commands {
     Modules,
     Conflicts,
     Diagnostics,
+    Unmount,
+    Reload,
     #[command(name = "system-action")]
     SystemAction {
         #[arg(long)]
@@ -56,4 +68,18 @@ pub enum Commands {
         #[arg(long)]
         value: Option<String>,
     },
+    // Spawned by reload to swap one overlay from a single-threaded process.
+    #[command(name = "swap-overlay", hide = true)]
+    SwapOverlay {
+        #[arg(long)]
+        target: String,
+        #[arg(long = "lowerdir")]
+        lowerdirs: Vec<String>,
+        #[arg(long)]
+        workdir: Option<PathBuf>,
+        #[arg(long)]
+        upperdir: Option<PathBuf>,
+        #[arg(long)]
+        disable_umount: bool,
+    },
 }
@@ -1,28 +1,61 @@
 // Copyright 2025 Meta-Hybrid Mount Authors
 // SPDX-License-Identifier: GPL-3.0-or-later
 
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use anyhow::{Context, Result, bail};
 use serde::Serialize;
 
 use crate::{
     conf::{
-        cli::Cli,
+        cli::{Cli, OutputFormat},
         config::{CONFIG_FILE_DEFAULT, Config},
     },
-    core::{executor, granary, inventory, modules, planner, storage, winnow},
+    core::{
+        digest::HashCache,
+        executor::{self, DiagnosticIssue, DiagnosticLevel},
+        granary,
+        inventory::{self, Module},
+        manifest::ExecutionManifest,
+        modules,
+        planner::{self, ConflictReport, ContentVerdict, MountPlan},
+        reload,
+        state::RuntimeState,
+        storage,
+        winnow::{self, ChaffConflict},
+    },
+    defs,
+    mount::{journal, overlay},
     utils,
 };
 
 #[derive(Serialize)]
-struct DiagnosticIssueJson {
-    level: String,
-    context: String,
-    message: String,
+struct DryRunReport<'a> {
+    modules: &'a [Module],
+    plan: &'a MountPlan,
+    conflicts: Vec<ChaffConflict>,
+    diagnostics: Vec<DiagnosticIssue>,
+    critical: usize,
 }
 
-fn load_config(cli: &Cli) -> Result<Config> {
+// The config as the commands see it, with the global CLI overrides applied.
+pub fn load_config(cli: &Cli) -> Result<Config> {
+    let mut config = load_stored_config(cli)?;
+
+    config.merge_with_cli(
+        cli.moduledir.clone(),
+        cli.mountsource.clone(),
+        cli.verbose,
+        cli.partitions.clone(),
+        cli.dry_run,
+        cli.sysroot.clone(),
+    );
+
+    Ok(config)
+}
+
+// The config file alone, for the WebUI and commands that write it back.
+fn load_stored_config(cli: &Cli) -> Result<Config> {
     if let Some(config_path) = &cli.config {
         return Config::from_file(config_path).with_context(|| {
             format!(
@@ -60,7 +93,7 @@ pub fn handle_gen_config(output: &Path) -> Result<()> {
 }
 
 pub fn handle_show_config(cli: &Cli) -> Result<()> {
-    let config = load_config(cli)?;
+    let config = load_stored_config(cli)?;
 
     let json = serde_json::to_string(&config).context("Failed to serialize config to JSON")?;
 
@@ -70,7 +103,7 @@ pub fn handle_show_config(cli: &Cli) -> Result<()> {
 }
 
 pub fn handle_save_config(cli: &Cli, payload: &str) -> Result<()> {
-    if let Ok(old_config) = load_config(cli)
+    if let Ok(old_config) = load_stored_config(cli)
         && let Err(e) = granary::create_silo(&old_config, "Auto-Backup", "Pre-WebUI Save")
     {
         log::warn!("Failed to create Granary backup: {}", e);
@@ -130,6 +163,18 @@ pub fn handle_modules(cli: &Cli) -> Result<()> {
     modules::print_list(&config).context("Failed to list modules")
 }
 
+fn analyze_conflicts(plan: &MountPlan, config: &Config) -> ConflictReport {
+    let cache = HashCache::load(Path::new(defs::HASH_CACHE_FILE));
+
+    let report = plan.analyze_conflicts(config, &cache);
+
+    if let Err(e) = cache.save() {
+        log::debug!("Hash cache not saved: {:#}", e);
+    }
+
+    report
+}
+
 pub fn handle_conflicts(cli: &Cli) -> Result<()> {
     let config = load_config(cli)?;
 
@@ -139,7 +184,7 @@ pub fn handle_conflicts(cli: &Cli) -> Result<()> {
     let plan = planner::generate(&config, &module_list, &config.moduledir)
         .context("Failed to generate plan for conflict analysis")?;
 
-    let report = plan.analyze_conflicts();
+    let report = analyze_conflicts(&plan, &config);
 
     let winnowed = winnow::sift_conflicts(report.details, &config.winnowing);
 
@@ -159,32 +204,252 @@ pub fn handle_diagnostics(cli: &Cli) -> Result<()> {
     let plan = planner::generate(&config, &module_list, &config.moduledir)
         .context("Failed to generate plan for diagnostics")?;
 
-    let issues = executor::diagnose_plan(&plan);
+    let mut issues = executor::diagnose_plan(&plan, &config.sysroot);
+
+    issues.extend(executor::diagnose_contexts(&module_list, &config.sysroot));
+
+    // The synced copies are what gets mounted, so audit their labels when the
+    // storage is up.
+    let state = RuntimeState::load().ok();
+
+    let storage_root = state
+        .as_ref()
+        .map(|state| state.mount_point.clone())
+        .filter(|root| utils::is_mounted(root));
+
+    let label_plan = match &storage_root {
+        Some(root) => Some(
+            planner::generate(&config, &module_list, root)
+                .context("Failed to generate plan for label audit")?,
+        ),
+        None => None,
+    };
+
+    issues.extend(executor::diagnose_labels(
+        label_plan.as_ref().unwrap_or(&plan),
+        &module_list,
+        &config.sysroot,
+        storage_root.as_deref(),
+    ));
+
+    if let Ok(manifest) = ExecutionManifest::load() {
+        issues.extend(manifest.diagnose());
+    }
+
+    if let Some(state) = &state {
+        issues.extend(state.diagnose());
+    }
+
+    let json = serde_json::to_string(&issues).context("Failed to serialize diagnostics report")?;
+
+    println!("{}", json);
+
+    Ok(())
+}
 
-    let json_issues: Vec<DiagnosticIssueJson> = issues
+pub fn handle_dry_run(config: &Config, format: OutputFormat) -> Result<()> {
+    log::info!(":: DRY-RUN / DIAGNOSTIC MODE ::");
+
+    let module_list =
+        inventory::scan(&config.moduledir, config).context("Inventory scan failed")?;
+
+    log::info!(">> Inventory: Found {} modules", module_list.len());
+
+    let plan = planner::generate(config, &module_list, &config.moduledir)
+        .context("Plan generation failed")?;
+
+    let report = analyze_conflicts(&plan, config);
+
+    let winnowed = winnow::sift_conflicts(report.details, &config.winnowing);
+
+    let mut issues = executor::diagnose_plan(&plan, &config.sysroot);
+
+    issues.extend(executor::diagnose_contexts(&module_list, &config.sysroot));
+
+    issues.extend(executor::diagnose_labels(
+        &plan,
+        &module_list,
+        &config.sysroot,
+        None,
+    ));
+
+    let critical_count = issues
+        .iter()
+        .filter(|i| matches!(i.level, DiagnosticLevel::Critical))
+        .count();
+
+    if format == OutputFormat::Json {
+        let report = DryRunReport {
+            modules: &module_list,
+            plan: &plan,
+            conflicts: winnowed,
+            diagnostics: issues,
+            critical: critical_count,
+        };
+
+        let json = serde_json::to_string(&report).context("Failed to serialize dry-run report")?;
+
+        println!("{}", json);
+
+        if critical_count > 0 {
+            std::process::exit(1);
+        }
+
+        return Ok(());
+    }
+
+    plan.print_visuals();
+
+    log::info!(">> Analyzing File Conflicts...");
+
+    let (identical, divergent): (Vec<_>, Vec<_>) = winnowed
         .into_iter()
-        .map(|i| DiagnosticIssueJson {
-            level: match i.level {
-                executor::DiagnosticLevel::Info => "Info".to_string(),
-                executor::DiagnosticLevel::Warning => "Warning".to_string(),
-                executor::DiagnosticLevel::Critical => "Critical".to_string(),
-            },
-            context: i.context,
-            message: i.message,
-        })
-        .collect();
-
-    let json =
-        serde_json::to_string(&json_issues).context("Failed to serialize diagnostics report")?;
+        .partition(|c| c.verdict == ContentVerdict::Identical);
+
+    if divergent.is_empty() {
+        log::info!("   No file conflicts detected. Clean.");
+    } else {
+        log::warn!("!! DETECTED {} FILE CONFLICTS !!", divergent.len());
+
+        for c in divergent {
+            let status = if c.is_forced { "(FORCED)" } else { "" };
+
+            log::warn!(
+                "   [{:?}] {} <== {:?} ({:?}) >> Selected: {} {}",
+                c.kind,
+                c.path.display(),
+                c.contenders,
+                c.scope,
+                c.selected,
+                status
+            );
+        }
+    }
+
+    if !identical.is_empty() {
+        log::info!("   Ignoring {} identical copies", identical.len());
+
+        for c in identical {
+            log::debug!("   [IDENTICAL] {} <== {:?}", c.path.display(), c.contenders);
+        }
+    }
+
+    log::info!(">> Running System Diagnostics...");
+
+    for issue in issues {
+        match issue.level {
+            DiagnosticLevel::Critical => {
+                log::error!("[CRITICAL][{}] {}", issue.context, issue.message);
+            }
+            DiagnosticLevel::Warning => {
+                log::warn!("[WARN][{}] {}", issue.context, issue.message);
+            }
+            DiagnosticLevel::Info => {
+                log::info!("[INFO][{}] {}", issue.context, issue.message);
+            }
+        }
+    }
+
+    if critical_count > 0 {
+        log::error!(
+            ">> ❌ DIAGNOSTICS FAILED: {} critical issues found.",
+            critical_count
+        );
+
+        log::error!(">> Mounting now would likely result in a bootloop.");
+
+        std::process::exit(1);
+    }
+
+    log::info!(">> ✅ Diagnostics passed. System looks healthy.");
+
+    Ok(())
+}
+
+pub fn handle_unmount() -> Result<()> {
+    let mut manifest = ExecutionManifest::load().context("Failed to load execution manifest")?;
+
+    let report = journal::unwind(&manifest.mounts);
+
+    let json = serde_json::to_string(&report).context("Failed to serialize teardown report")?;
 
     println!("{}", json);
 
+    manifest
+        .mounts
+        .retain(|r| report.failed.iter().any(|f| f.target == r.target));
+
+    if report.failed.is_empty() {
+        manifest.overlays.clear();
+
+        manifest.magic_modules.clear();
+
+        manifest.magic_paths.clear();
+
+        manifest.pins.clear();
+
+        let mut state = RuntimeState::load().context("Failed to load runtime state")?;
+
+        state.overlay_modules.clear();
+
+        state.magic_modules.clear();
+
+        state.active_mounts.clear();
+
+        state.save().context("Failed to update runtime state")?;
+    }
+
+    manifest
+        .save()
+        .context("Failed to update execution manifest")?;
+
+    if !report.failed.is_empty() {
+        bail!("{} mounts could not be detached", report.failed.len());
+    }
+
     Ok(())
 }
 
-pub fn handle_system_action(cli: &Cli, action: &str, value: Option<&str>) -> Result<()> {
+pub fn handle_reload(cli: &Cli) -> Result<()> {
     let mut config = load_config(cli)?;
 
+    if utils::check_zygisksu_enforce_status() && !config.allow_umount_coexistence {
+        config.disable_umount = true;
+    }
+
+    let report = reload::reload(&config).context("Failed to reload modules")?;
+
+    let json = serde_json::to_string(&report).context("Failed to serialize reload report")?;
+
+    println!("{}", json);
+
+    if !report.failed.is_empty() {
+        bail!("{} partitions could not be reloaded", report.failed.len());
+    }
+
+    Ok(())
+}
+
+// Must stay free of threads: the swap calls setns.
+pub fn handle_swap_overlay(
+    target: &str,
+    lowerdirs: &[String],
+    workdir: Option<PathBuf>,
+    upperdir: Option<PathBuf>,
+    disable_umount: bool,
+) -> Result<()> {
+    let reply = overlay::swap_in_private_ns(target, lowerdirs, workdir, upperdir, disable_umount)?;
+
+    let json = serde_json::to_string(&reply).context("Failed to serialize swap reply")?;
+
+    println!("{}", json);
+
+    Ok(())
+}
+
+pub fn handle_system_action(cli: &Cli, action: &str, value: Option<&str>) -> Result<()> {
+    let mut config = load_stored_config(cli)?;
+
     match action {
         "granary-list" => {
             let silos = granary::list_silos()?;
@@ -10,6 +10,8 @@ use std::{
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
 
+use super::pattern;
+
 pub const CONFIG_FILE_DEFAULT: &str = "/data/adb/meta-hybrid/config.toml";
 
 #[derive(Debug, Serialize, Deserialize, Clone, Default)]
@@ -18,11 +20,22 @@ pub struct WinnowingTable {
     pub rules: HashMap<String, String>,
 }
 
-impl WinnowingTable {
-    pub fn get_preferred_module(&self, file_path: &Path) -> Option<String> {
-        let path_str = file_path.to_string_lossy().to_string();
+#[derive(Debug, Clone, PartialEq)]
+pub struct PreferredModule {
+    pub module_id: String,
+    pub pattern: String,
+}
 
-        self.rules.get(&path_str).cloned()
+impl WinnowingTable {
+    pub fn get_preferred_module(&self, file_path: &Path) -> Option<PreferredModule> {
+        let path_str = file_path.to_string_lossy();
+
+        pattern::best_match(&self.rules, |p| pattern::match_exact(p, &path_str)).map(
+            |(pattern, module_id)| PreferredModule {
+                module_id: module_id.clone(),
+                pattern: pattern.to_string(),
+            },
+        )
     }
 
     pub fn set_rule(&mut self, file_path: &str, module_id: &str) {
@@ -82,6 +95,14 @@ pub struct Config {
     pub allow_umount_coexistence: bool,
     #[serde(default)]
     pub dry_run: bool,
+    // Roll back every mount of the run when magic mount fails, instead of
+    // keeping whatever was already mounted.
+    #[serde(default)]
+    pub strict_mount: bool,
+    #[serde(default = "default_sysroot")]
+    pub sysroot: PathBuf,
+    #[serde(default)]
+    pub priority: Vec<String>,
     #[serde(default)]
     pub winnowing: WinnowingTable,
     #[serde(default)]
@@ -92,6 +113,10 @@ fn default_moduledir() -> PathBuf {
     PathBuf::from("/data/adb/modules/")
 }
 
+fn default_sysroot() -> PathBuf {
+    PathBuf::from("/")
+}
+
 fn default_mountsource() -> String {
     String::from("KSU")
 }
@@ -130,6 +155,9 @@ impl Default for Config {
             disable_umount: false,
             allow_umount_coexistence: false,
             dry_run: false,
+            strict_mount: false,
+            sysroot: default_sysroot(),
+            priority: Vec::new(),
             winnowing: WinnowingTable::default(),
             granary: GranaryConfig::default(),
         }
@@ -168,6 +196,7 @@ impl Config {
         verbose: bool,
         partitions: Vec<String>,
         dry_run: bool,
+        sysroot: Option<PathBuf>,
     ) {
         if let Some(dir) = moduledir {
             self.moduledir = dir;
@@ -188,5 +217,9 @@ impl Config {
         if dry_run {
             self.dry_run = true;
         }
+
+        if let Some(root) = sysroot {
+            self.sysroot = root;
+        }
     }
 }
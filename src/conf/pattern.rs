@@ -0,0 +1,331 @@
+// Copyright 2025 Meta-Hybrid Mount Authors
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    sync::{Mutex, OnceLock},
+};
+
+use regex_lite::Regex;
+
+const REGEX_PREFIX: &str = "re:";
+
+static COMPILED: OnceLock<Mutex<HashMap<String, Option<Regex>>>> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PatternKind {
+    Regex,
+    Glob,
+    Literal,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Specificity {
+    pub matched_len: usize,
+    pub kind: PatternKind,
+    pub literal_chars: usize,
+}
+
+impl Ord for Specificity {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.matched_len
+            .cmp(&other.matched_len)
+            .then(self.kind.cmp(&other.kind))
+            .then(self.literal_chars.cmp(&other.literal_chars))
+    }
+}
+
+impl PartialOrd for Specificity {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+pub fn normalize(path: &str) -> &str {
+    path.trim_matches('/')
+}
+
+pub fn kind_of(pattern: &str) -> PatternKind {
+    if pattern.starts_with(REGEX_PREFIX) {
+        PatternKind::Regex
+    } else if pattern.contains(['*', '?', '[']) {
+        PatternKind::Glob
+    } else {
+        PatternKind::Literal
+    }
+}
+
+fn literal_chars(pattern: &str) -> usize {
+    match kind_of(pattern) {
+        PatternKind::Regex => 0,
+        PatternKind::Glob => normalize(pattern)
+            .chars()
+            .filter(|c| !matches!(c, '*' | '?' | '[' | ']'))
+            .count(),
+        PatternKind::Literal => normalize(pattern).len(),
+    }
+}
+
+// Text before the first wildcard; nothing outside of it can be matched.
+fn literal_head(pattern: &str) -> Option<&str> {
+    match kind_of(pattern) {
+        PatternKind::Regex => None,
+        PatternKind::Glob => {
+            let normalized = normalize(pattern);
+
+            let end = normalized.find(['*', '?', '[']).unwrap_or(normalized.len());
+
+            Some(&normalized[..end])
+        }
+        PatternKind::Literal => Some(normalize(pattern)),
+    }
+}
+
+fn glob_to_regex(glob: &str) -> String {
+    let chars: Vec<char> = normalize(glob).chars().collect();
+
+    let mut out = String::from("^");
+
+    let mut i = 0;
+
+    while i < chars.len() {
+        match chars[i] {
+            '*' if chars.get(i + 1) == Some(&'*') => {
+                if chars.get(i + 2) == Some(&'/') {
+                    out.push_str("(?:.*/)?");
+
+                    i += 3;
+                } else {
+                    out.push_str(".*");
+
+                    i += 2;
+                }
+
+                continue;
+            }
+            '*' => out.push_str("[^/]*"),
+            '?' => out.push_str("[^/]"),
+            '[' => match chars[i..].iter().position(|&c| c == ']') {
+                Some(len) if len > 1 => {
+                    out.push('[');
+
+                    let mut class = &chars[i + 1..i + len];
+
+                    if class.first() == Some(&'!') {
+                        out.push('^');
+
+                        class = &class[1..];
+                    }
+
+                    for c in class {
+                        if *c == '\\' {
+                            out.push('\\');
+                        }
+
+                        out.push(*c);
+                    }
+
+                    out.push(']');
+
+                    i += len + 1;
+
+                    continue;
+                }
+                _ => out.push_str("\\["),
+            },
+            c => {
+                let mut buf = [0u8; 4];
+
+                out.push_str(&regex_lite::escape(c.encode_utf8(&mut buf)));
+            }
+        }
+
+        i += 1;
+    }
+
+    out.push('$');
+
+    out
+}
+
+fn compiled(pattern: &str) -> Option<Regex> {
+    let cache = COMPILED.get_or_init(|| Mutex::new(HashMap::new()));
+
+    let mut cache = cache.lock().unwrap();
+
+    if let Some(re) = cache.get(pattern) {
+        return re.clone();
+    }
+
+    let source = match kind_of(pattern) {
+        PatternKind::Regex => pattern[REGEX_PREFIX.len()..].to_string(),
+        PatternKind::Glob => glob_to_regex(pattern),
+        PatternKind::Literal => format!("^{}$", regex_lite::escape(normalize(pattern))),
+    };
+
+    let re = match Regex::new(&source) {
+        Ok(re) => Some(re),
+        Err(e) => {
+            log::warn!("Ignoring invalid path pattern '{}': {}", pattern, e);
+
+            None
+        }
+    };
+
+    cache.insert(pattern.to_string(), re.clone());
+
+    re
+}
+
+pub fn is_match(pattern: &str, path: &str) -> bool {
+    let path = normalize(path);
+
+    match kind_of(pattern) {
+        PatternKind::Literal => normalize(pattern) == path,
+        _ => compiled(pattern).is_some_and(|re| re.is_match(path)),
+    }
+}
+
+pub fn match_exact(pattern: &str, path: &str) -> Option<Specificity> {
+    is_match(pattern, path).then(|| Specificity {
+        matched_len: normalize(path).len(),
+        kind: kind_of(pattern),
+        literal_chars: literal_chars(pattern),
+    })
+}
+
+// Matches the path itself or any of its ancestors, preferring the deepest.
+pub fn match_prefix(pattern: &str, path: &str) -> Option<Specificity> {
+    let path = normalize(path);
+
+    let mut candidate = path;
+
+    loop {
+        if !candidate.is_empty()
+            && let Some(spec) = match_exact(pattern, candidate)
+        {
+            return Some(spec);
+        }
+
+        match candidate.rfind('/') {
+            Some(idx) => candidate = &candidate[..idx],
+            None => return None,
+        }
+    }
+}
+
+// Whether the pattern might match an entry strictly below `dir`.
+pub fn may_match_below(pattern: &str, dir: &str) -> bool {
+    let dir = normalize(dir);
+
+    if dir.is_empty() {
+        return true;
+    }
+
+    let Some(head) = literal_head(pattern) else {
+        return true;
+    };
+
+    if kind_of(pattern) == PatternKind::Literal {
+        return head
+            .strip_prefix(dir)
+            .is_some_and(|rest| rest.starts_with('/'));
+    }
+
+    if head.len() > dir.len() {
+        head.starts_with(dir) && head[dir.len()..].starts_with('/')
+    } else {
+        dir.starts_with(head)
+    }
+}
+
+pub fn best_match<'a, T, I, F>(rules: I, matcher: F) -> Option<(&'a str, &'a T)>
+where
+    I: IntoIterator<Item = (&'a String, &'a T)>,
+    F: Fn(&str) -> Option<Specificity>,
+{
+    rules
+        .into_iter()
+        .filter_map(|(pattern, value)| matcher(pattern).map(|spec| (spec, pattern, value)))
+        .max_by(|a, b| a.0.cmp(&b.0).then_with(|| b.1.cmp(a.1)))
+        .map(|(_, pattern, value)| (pattern.as_str(), value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn winner(rules: &[(&str, &str)], path: &str) -> Option<String> {
+        let rules: HashMap<String, String> = rules
+            .iter()
+            .map(|(p, v)| (p.to_string(), v.to_string()))
+            .collect();
+
+        best_match(&rules, |p| match_prefix(p, path)).map(|(_, v)| v.clone())
+    }
+
+    #[test]
+    fn literal_beats_glob_beats_regex_on_the_same_path() {
+        let rules = [
+            ("re:^system/etc/.*$", "regex"),
+            ("system/etc/*.conf", "glob"),
+            ("system/etc/hosts.conf", "literal"),
+        ];
+
+        assert_eq!(
+            winner(&rules, "system/etc/hosts.conf").as_deref(),
+            Some("literal")
+        );
+
+        assert_eq!(
+            winner(&rules, "system/etc/other.conf").as_deref(),
+            Some("glob")
+        );
+
+        assert_eq!(
+            winner(&rules, "system/etc/other.txt").as_deref(),
+            Some("regex")
+        );
+    }
+
+    #[test]
+    fn deeper_match_beats_pattern_kind() {
+        let rules = [("system", "literal"), ("system/**/*.so", "glob")];
+
+        assert_eq!(
+            winner(&rules, "system/lib64/libfoo.so").as_deref(),
+            Some("glob")
+        );
+
+        assert_eq!(winner(&rules, "system/bin/sh").as_deref(), Some("literal"));
+
+        let shallow = Specificity {
+            matched_len: 9,
+            kind: PatternKind::Literal,
+            literal_chars: 9,
+        };
+
+        let deep = Specificity {
+            matched_len: 10,
+            kind: PatternKind::Regex,
+            literal_chars: 0,
+        };
+
+        assert!(deep > shallow);
+    }
+
+    #[test]
+    fn glob_with_more_literal_text_wins() {
+        let rules = [("system/*/hosts", "loose"), ("system/etc/host?", "tight")];
+
+        assert_eq!(winner(&rules, "system/etc/hosts").as_deref(), Some("tight"));
+    }
+
+    #[test]
+    fn ties_go_to_the_pattern_that_sorts_first() {
+        let rules = [("system/etc/x?", "later"), ("system/etc/?x", "earlier")];
+
+        assert_eq!(winner(&rules, "system/etc/xx").as_deref(), Some("earlier"));
+    }
+}
@@ -0,0 +1,411 @@
+// Copyright 2025 Meta-Hybrid Mount Authors
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{
+    collections::HashSet,
+    fs::{self, FileType},
+    os::unix::fs::FileTypeExt,
+    path::Path,
+};
+
+use regex_lite::Regex;
+use walkdir::WalkDir;
+
+use crate::{core::inventory::ModuleRules, defs};
+
+// Later files override earlier ones, matching the order init loads them in.
+const CONTEXT_FILES: &[&str] = &[
+    "plat_file_contexts",
+    "system/etc/selinux/plat_file_contexts",
+    "system_ext/etc/selinux/system_ext_file_contexts",
+    "system/system_ext/etc/selinux/system_ext_file_contexts",
+    "product/etc/selinux/product_file_contexts",
+    "system/product/etc/selinux/product_file_contexts",
+    "vendor_file_contexts",
+    "vendor/etc/selinux/nonplat_file_contexts",
+    "vendor/etc/selinux/vendor_file_contexts",
+    "odm/etc/selinux/odm_file_contexts",
+];
+
+const META_CHARS: &[char] = &['.', '^', '$', '?', '*', '+', '|', '[', '(', '{', '\\'];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SpecKind {
+    Any,
+    File,
+    Dir,
+    Symlink,
+    Char,
+    Block,
+    Socket,
+    Pipe,
+}
+
+impl SpecKind {
+    fn parse(flag: &str) -> Option<Self> {
+        match flag {
+            "--" => Some(Self::File),
+            "-d" => Some(Self::Dir),
+            "-l" => Some(Self::Symlink),
+            "-c" => Some(Self::Char),
+            "-b" => Some(Self::Block),
+            "-s" => Some(Self::Socket),
+            "-p" => Some(Self::Pipe),
+            _ => None,
+        }
+    }
+
+    fn matches(self, file_type: FileType) -> bool {
+        match self {
+            Self::Any => true,
+            Self::File => file_type.is_file(),
+            Self::Dir => file_type.is_dir(),
+            Self::Symlink => file_type.is_symlink(),
+            Self::Char => file_type.is_char_device(),
+            Self::Block => file_type.is_block_device(),
+            Self::Socket => file_type.is_socket(),
+            Self::Pipe => file_type.is_fifo(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Spec {
+    // Literal prefix every match has to start with, checked before the regex.
+    stem: String,
+    regex: Regex,
+    kind: SpecKind,
+    // `<<none>>` resolves to nothing, leaving the caller's fallback in charge.
+    context: Option<String>,
+    literal: bool,
+}
+
+impl Spec {
+    fn parse(line: &str) -> Result<Self, String> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+
+        let (pattern, kind, context) = match fields.as_slice() {
+            [pattern, context] => (*pattern, SpecKind::Any, *context),
+            [pattern, flag, context] => match SpecKind::parse(flag) {
+                Some(kind) => (*pattern, kind, *context),
+                None => return Err(format!("unknown file type '{}'", flag)),
+            },
+            _ => return Err("expected '<path regex> [file type] <context>'".to_string()),
+        };
+
+        if context != "<<none>>" && !is_valid_context(context) {
+            return Err(format!("malformed context '{}'", context));
+        }
+
+        let regex = Regex::new(&format!("^(?:{})$", pattern))
+            .map_err(|e| format!("invalid regex '{}': {}", pattern, e))?;
+
+        let mut stem: String = pattern
+            .chars()
+            .take_while(|c| !META_CHARS.contains(c))
+            .collect();
+
+        // A quantifier makes the character before it optional.
+        if pattern[stem.len()..].starts_with(['?', '*', '{']) {
+            stem.pop();
+        }
+
+        Ok(Self {
+            literal: !has_meta_chars(pattern),
+            stem,
+            regex,
+            kind,
+            context: (context != "<<none>>").then(|| context.to_string()),
+        })
+    }
+}
+
+// user:role:type:level, where the MLS level may itself contain colons.
+fn is_valid_context(context: &str) -> bool {
+    let fields: Vec<&str> = context.splitn(4, ':').collect();
+
+    fields.len() == 4 && fields.iter().all(|f| !f.is_empty())
+}
+
+fn type_of(context: &str) -> Option<&str> {
+    context.split(':').nth(2)
+}
+
+// Escaped characters are literal, as in libselinux.
+fn has_meta_chars(pattern: &str) -> bool {
+    let mut chars = pattern.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            c if META_CHARS.contains(&c) => return true,
+            _ => {}
+        }
+    }
+
+    false
+}
+
+// Resolves labels the way restorecon does: the last matching entry wins, and
+// entries without regex syntax beat every pattern.
+#[derive(Debug, Default)]
+pub struct FileContexts {
+    specs: Vec<Spec>,
+}
+
+impl FileContexts {
+    pub fn load(sysroot: &Path) -> Self {
+        let mut contexts = Self::default();
+
+        let mut seen = HashSet::new();
+
+        for relative in CONTEXT_FILES {
+            let path = sysroot.join(relative);
+
+            // system_ext and friends are often reachable through /system too.
+            let Ok(canonical) = path.canonicalize() else {
+                continue;
+            };
+
+            if !seen.insert(canonical) {
+                continue;
+            }
+
+            match fs::read_to_string(&path) {
+                Ok(content) => contexts.extend(&content),
+                Err(e) => log::debug!("Cannot read {}: {}", path.display(), e),
+            }
+        }
+
+        contexts.finish();
+
+        log::debug!("Loaded {} file_contexts entries", contexts.specs.len());
+
+        contexts
+    }
+
+    // Overrides shipped by a module: its own file_contexts, then the `contexts`
+    // section of its rules, which wins where both match.
+    pub fn for_module(module_dir: &Path, rules: &ModuleRules) -> Self {
+        let mut contexts = Self::default();
+
+        for (entry, spec) in module_entries(module_dir, rules) {
+            match spec {
+                Ok(spec) => contexts.specs.push(spec),
+                Err(reason) => log::warn!(
+                    "Ignoring context override '{}' in {}: {}",
+                    entry,
+                    module_dir.display(),
+                    reason
+                ),
+            }
+        }
+
+        contexts.finish();
+
+        contexts
+    }
+
+    fn extend(&mut self, content: &str) {
+        self.specs.extend(
+            entry_lines(content).filter_map(|line| match Spec::parse(line) {
+                Ok(spec) => Some(spec),
+                Err(reason) => {
+                    log::debug!("Skipping file_contexts entry '{}': {}", line, reason);
+
+                    None
+                }
+            }),
+        );
+    }
+
+    fn finish(&mut self) {
+        self.specs.sort_by_key(|spec| spec.literal);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.specs.is_empty()
+    }
+
+    fn types(&self) -> HashSet<&str> {
+        self.specs
+            .iter()
+            .filter_map(|spec| spec.context.as_deref().and_then(type_of))
+            .collect()
+    }
+
+    // `path` is the absolute path on the device, not the one under sysroot.
+    pub fn lookup(&self, path: &Path, file_type: FileType) -> Option<&str> {
+        let path = path.to_string_lossy();
+
+        self.specs
+            .iter()
+            .rev()
+            .find(|spec| {
+                path.starts_with(&spec.stem)
+                    && spec.kind.matches(file_type)
+                    && spec.regex.is_match(&path)
+            })
+            .and_then(|spec| spec.context.as_deref())
+    }
+}
+
+fn entry_lines(content: &str) -> impl Iterator<Item = &str> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+}
+
+fn module_entries(module_dir: &Path, rules: &ModuleRules) -> Vec<(String, Result<Spec, String>)> {
+    let content =
+        fs::read_to_string(module_dir.join(defs::MODULE_FILE_CONTEXTS)).unwrap_or_default();
+
+    entry_lines(&content)
+        .map(str::to_string)
+        .chain(
+            rules
+                .contexts
+                .iter()
+                .map(|(pattern, context)| format!("{} {}", pattern, context)),
+        )
+        .map(|entry| {
+            let spec = Spec::parse(&entry);
+
+            (entry, spec)
+        })
+        .collect()
+}
+
+#[derive(Debug)]
+pub enum ContextFinding {
+    Invalid { entry: String, reason: String },
+    UnknownType { entry: String, context: String },
+    Unmatched { entry: String },
+}
+
+// Checks a module's overrides against the files it ships and the types the
+// device policy knows about. Unknown types are only reported when the device
+// file_contexts could be read.
+pub fn audit_module(
+    module_dir: &Path,
+    rules: &ModuleRules,
+    device: &FileContexts,
+) -> Vec<ContextFinding> {
+    let known_types = device.types();
+
+    let shipped: Vec<(String, FileType)> = defs::BUILTIN_PARTITIONS
+        .iter()
+        .flat_map(|part| WalkDir::new(module_dir.join(part)).into_iter().flatten())
+        .filter_map(|entry| {
+            let relative = entry.path().strip_prefix(module_dir).ok()?;
+
+            Some((
+                Path::new("/").join(relative).to_string_lossy().to_string(),
+                entry.file_type(),
+            ))
+        })
+        .collect();
+
+    let mut findings = Vec::new();
+
+    for (entry, spec) in module_entries(module_dir, rules) {
+        let spec = match spec {
+            Ok(spec) => spec,
+            Err(reason) => {
+                findings.push(ContextFinding::Invalid { entry, reason });
+
+                continue;
+            }
+        };
+
+        if let Some(context) = &spec.context
+            && !known_types.is_empty()
+            && type_of(context).is_some_and(|t| !known_types.contains(t))
+        {
+            findings.push(ContextFinding::UnknownType {
+                entry: entry.clone(),
+                context: context.clone(),
+            });
+        }
+
+        let matches_any = shipped.iter().any(|(path, file_type)| {
+            path.starts_with(&spec.stem)
+                && spec.kind.matches(*file_type)
+                && spec.regex.is_match(path)
+        });
+
+        if !matches_any {
+            findings.push(ContextFinding::Unmatched { entry });
+        }
+    }
+
+    findings
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use anyhow::ensure;
+
+    use super::FileContexts;
+    use crate::core::tests::simulated;
+
+    #[test]
+    fn file_contexts_resolve_like_restorecon() {
+        simulated("file_contexts_resolve_like_restorecon", |fx| {
+            fx.system_file(
+                "system/etc/selinux/plat_file_contexts",
+                r#"
+# comment
+/system(/.*)?                   u:object_r:system_file:s0
+/system/bin/hw/.*               u:object_r:hal_exec:s0
+/system/lib(64)?/.*\.so         u:object_r:system_lib_file:s0
+/system/bin/hw/special          u:object_r:special_exec:s0
+/system/bin/hw(/.*)?     -d     u:object_r:hal_dir:s0
+/system/etc/none                <<none>>
+"#,
+            )?;
+
+            fx.system_file(
+                "vendor/etc/selinux/vendor_file_contexts",
+                "/system/bin/hw/.*  u:object_r:vendor_override:s0\n",
+            )?;
+
+            let contexts = FileContexts::load(&fx.sysroot);
+
+            let file =
+                fs::symlink_metadata(fx.sysroot.join("vendor/etc/selinux/vendor_file_contexts"))?
+                    .file_type();
+
+            let dir = fs::symlink_metadata(&fx.sysroot)?.file_type();
+
+            let label = |path: &str, file_type| contexts.lookup(Path::new(path), file_type);
+
+            ensure!(label("/system/xbin/su", file) == Some("u:object_r:system_file:s0"));
+
+            ensure!(
+                label("/system/lib64/libfoo.so", file) == Some("u:object_r:system_lib_file:s0")
+            );
+
+            // Later files win over earlier ones.
+            ensure!(label("/system/bin/hw/foo", file) == Some("u:object_r:vendor_override:s0"));
+
+            // Literal paths beat every pattern, and type flags restrict matches.
+            ensure!(label("/system/bin/hw/special", file) == Some("u:object_r:special_exec:s0"));
+
+            ensure!(label("/system/bin/hw", dir) == Some("u:object_r:hal_dir:s0"));
+
+            ensure!(label("/system/bin/hw", file) == Some("u:object_r:system_file:s0"));
+
+            ensure!(label("/system/etc/none", file).is_none());
+
+            ensure!(label("/data/local/tmp", dir).is_none());
+
+            Ok(())
+        });
+    }
+}
@@ -0,0 +1,175 @@
+// Copyright 2025 Meta-Hybrid Mount Authors
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::Read,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+// Bumped whenever the hash function changes, so stale caches are discarded.
+const CACHE_VERSION: u32 = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct CachedHash {
+    mtime: i64,
+    mtime_nsec: i64,
+    size: u64,
+    hash: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CacheFile {
+    version: u32,
+    entries: HashMap<String, CachedHash>,
+}
+
+// Content hashes keyed by device and inode. An entry is reused only while the
+// file keeps the mtime and size it had when it was hashed.
+#[derive(Debug, Default)]
+pub struct HashCache {
+    path: Option<PathBuf>,
+    previous: HashMap<String, CachedHash>,
+    // Only entries looked up in this run are written back, which prunes files
+    // that have disappeared since.
+    current: Mutex<HashMap<String, CachedHash>>,
+}
+
+impl HashCache {
+    pub fn load(path: &Path) -> Self {
+        let previous = fs::read_to_string(path)
+            .ok()
+            .and_then(|content| serde_json::from_str::<CacheFile>(&content).ok())
+            .filter(|file| file.version == CACHE_VERSION)
+            .map(|file| file.entries)
+            .unwrap_or_default();
+
+        Self {
+            path: Some(path.to_path_buf()),
+            previous,
+            current: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn hash(&self, path: &Path) -> Result<String> {
+        let metadata =
+            fs::metadata(path).with_context(|| format!("failed to stat {}", path.display()))?;
+
+        let key = format!("{}:{}", metadata.dev(), metadata.ino());
+
+        let fresh = |entry: &CachedHash| {
+            entry.mtime == metadata.mtime()
+                && entry.mtime_nsec == metadata.mtime_nsec()
+                && entry.size == metadata.size()
+        };
+
+        let cached = self
+            .current
+            .lock()
+            .unwrap()
+            .get(&key)
+            .or_else(|| self.previous.get(&key))
+            .filter(|entry| fresh(entry))
+            .cloned();
+
+        let entry = match cached {
+            Some(entry) => entry,
+            None => CachedHash {
+                mtime: metadata.mtime(),
+                mtime_nsec: metadata.mtime_nsec(),
+                size: metadata.size(),
+                hash: hash_file(path)?,
+            },
+        };
+
+        let hash = entry.hash.clone();
+
+        self.current.lock().unwrap().insert(key, entry);
+
+        Ok(hash)
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        let file = CacheFile {
+            version: CACHE_VERSION,
+            entries: self.current.lock().unwrap().clone(),
+        };
+
+        fs::write(path, serde_json::to_string(&file)?)
+            .with_context(|| format!("failed to write {}", path.display()))
+    }
+}
+
+// SHA-256 of the content. The result is persisted in caches and sync manifests,
+// so it must not depend on the toolchain.
+pub fn hash_file(path: &Path) -> Result<String> {
+    let mut file =
+        fs::File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+
+    let mut hasher = Sha256::new();
+
+    let mut buf = vec![0u8; 64 * 1024];
+
+    loop {
+        let read = file.read(&mut buf)?;
+
+        if read == 0 {
+            break;
+        }
+
+        hasher.update(&buf[..read]);
+    }
+
+    Ok(hex(&hasher.finalize()))
+}
+
+// SHA-256 over every extended attribute of `path`, sorted by name. None when the
+// entry carries none.
+pub fn hash_xattrs(path: &Path) -> Option<String> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        let mut names = extattr::llistxattr(path).unwrap_or_default();
+
+        if names.is_empty() {
+            return None;
+        }
+
+        names.sort();
+
+        let mut hasher = Sha256::new();
+
+        for name in names {
+            let value = extattr::lgetxattr(path, &name).unwrap_or_default();
+
+            hasher.update(name.as_encoded_bytes());
+
+            hasher.update((value.len() as u64).to_le_bytes());
+
+            hasher.update(&value);
+        }
+
+        Some(hex(&hasher.finalize()))
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    {
+        let _ = path;
+
+        None
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
@@ -2,40 +2,71 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
+    os::unix::fs::PermissionsExt,
     path::{Path, PathBuf},
-    process::Command,
 };
 
 use anyhow::Result;
 use rayon::prelude::*;
-use rustix::mount::UnmountFlags;
+use serde::Serialize;
 use walkdir::WalkDir;
 
 use crate::{
     conf::config,
-    core::planner::MountPlan,
+    core::{
+        contexts::{self, ContextFinding, FileContexts},
+        inventory::Module,
+        planner::{ForcedSelection, MountPlan, OverlayOperation},
+        state::OverlayCulprit,
+    },
     defs,
-    mount::{magic, overlay},
+    error::{ErrorCode, ErrorReport},
+    mount::{backend::MountBackend, journal::TeardownReport, magic::MagicRequest},
     utils,
 };
 
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use crate::try_umount::send_unmountable;
+
+pub struct OverlayFallback {
+    pub partition_name: String,
+    pub target: String,
+    pub module_ids: Vec<String>,
+    pub error: ErrorReport,
+    // The overlay was mounted after all, without the layers of `module_ids`.
+    pub partial: bool,
+}
+
 pub struct ExecutionResult {
     pub overlay_module_ids: Vec<String>,
     pub magic_module_ids: Vec<String>,
+    pub fallbacks: Vec<OverlayFallback>,
+    pub culprits: Vec<OverlayCulprit>,
+    pub magic_error: Option<ErrorReport>,
+    // What magic mount was asked to project, kept for the verification pass.
+    pub magic_paths: Vec<PathBuf>,
+    pub magic_exclusions: HashMap<PathBuf, HashSet<String>>,
+    // Set when strict mode took the whole run back down.
+    pub rollback: Option<TeardownReport>,
 }
 
+#[derive(Serialize)]
 pub enum DiagnosticLevel {
-    #[allow(dead_code)]
     Info,
     Warning,
     Critical,
 }
 
+#[derive(Serialize)]
 pub struct DiagnosticIssue {
     pub level: DiagnosticLevel,
     pub context: String,
     pub message: String,
+    pub code: Option<ErrorCode>,
+    // The device path the issue is about, where there is one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
 }
 
 fn extract_id(path: &Path) -> Option<String> {
@@ -50,11 +81,12 @@ fn extract_module_root(partition_path: &Path) -> Option<PathBuf> {
 
 struct OverlayResult {
     magic_roots: Vec<PathBuf>,
-    fallback_ids: Vec<String>,
+    fallback: Option<OverlayFallback>,
     success_records: Vec<(PathBuf, String)>,
+    culprits: Vec<OverlayCulprit>,
 }
 
-fn repair_rw_contexts() {
+fn repair_rw_contexts(sysroot: &Path, backend: &dyn MountBackend) {
     let rw_root = Path::new(defs::SYSTEM_RW_DIR);
 
     if !rw_root.exists() {
@@ -66,44 +98,22 @@ fn repair_rw_contexts() {
     for part in defs::BUILTIN_PARTITIONS {
         let part_dir = rw_root.join(part);
 
-        let reference_path = Path::new("/").join(part);
+        let reference_path = sysroot.join(part);
 
         if part_dir.exists() && reference_path.exists() {
-            let status = Command::new("chcon")
-                .arg("-R")
-                .arg("--reference")
-                .arg(&reference_path)
-                .arg(&part_dir)
-                .status();
-
-            match status {
-                Ok(s) if s.success() => {
-                    log::debug!(
-                        "Fixed context for {} using reference {}",
-                        part_dir.display(),
-                        reference_path.display()
-                    );
-                }
-                _ => {
-                    let context = "u:object_r:system_file:s0";
-
-                    log::warn!(
-                        "chcon --reference failed, trying explicit context {}",
-                        context
-                    );
-
-                    let _ = Command::new("chcon")
-                        .arg("-R")
-                        .arg(context)
-                        .arg(&part_dir)
-                        .status();
-                }
+            match backend.relabel_tree(&part_dir, &reference_path) {
+                Ok(()) => log::debug!(
+                    "Fixed context for {} using reference {}",
+                    part_dir.display(),
+                    reference_path.display()
+                ),
+                Err(e) => log::warn!("Failed to relabel {}: {:#}", part_dir.display(), e),
             }
         }
     }
 }
 
-pub fn diagnose_plan(plan: &MountPlan) -> Vec<DiagnosticIssue> {
+pub fn diagnose_plan(plan: &MountPlan, sysroot: &Path) -> Vec<DiagnosticIssue> {
     let mut issues = Vec::new();
 
     for op in &plan.overlay_ops {
@@ -114,6 +124,8 @@ pub fn diagnose_plan(plan: &MountPlan) -> Vec<DiagnosticIssue> {
                 level: DiagnosticLevel::Critical,
                 context: op.partition_name.clone(),
                 message: format!("Target mount point does not exist: {}", op.target),
+                code: None,
+                path: Some(PathBuf::from(&op.target)),
             });
         }
     }
@@ -139,7 +151,7 @@ pub fn diagnose_plan(plan: &MountPlan) -> Vec<DiagnosticIssue> {
             if entry.path_is_symlink()
                 && let Ok(target) = std::fs::read_link(entry.path())
                 && target.is_absolute()
-                && !target.exists()
+                && !utils::under_root(sysroot, &target).exists()
             {
                 issues.push(DiagnosticIssue {
                     level: DiagnosticLevel::Warning,
@@ -149,6 +161,8 @@ pub fn diagnose_plan(plan: &MountPlan) -> Vec<DiagnosticIssue> {
                         entry.path().display(),
                         target.display()
                     ),
+                    code: None,
+                    path: Some(entry.path().to_path_buf()),
                 });
             }
         }
@@ -157,7 +171,348 @@ pub fn diagnose_plan(plan: &MountPlan) -> Vec<DiagnosticIssue> {
     issues
 }
 
-pub fn execute(plan: &MountPlan, config: &config::Config) -> Result<ExecutionResult> {
+pub fn diagnose_contexts(modules: &[Module], sysroot: &Path) -> Vec<DiagnosticIssue> {
+    let device = FileContexts::load(sysroot);
+
+    let mut issues = Vec::new();
+
+    for module in modules {
+        for finding in contexts::audit_module(&module.source_path, &module.rules, &device) {
+            let (level, message) = match finding {
+                ContextFinding::Invalid { entry, reason } => (
+                    DiagnosticLevel::Critical,
+                    format!("Invalid context override '{}': {}", entry, reason),
+                ),
+                ContextFinding::UnknownType { entry, context } => (
+                    DiagnosticLevel::Warning,
+                    format!(
+                        "Context override '{}' uses a type the device policy does not label with: {}",
+                        entry, context
+                    ),
+                ),
+                ContextFinding::Unmatched { entry } => (
+                    DiagnosticLevel::Info,
+                    format!("Context override '{}' matches no module file", entry),
+                ),
+            };
+
+            issues.push(DiagnosticIssue {
+                level,
+                context: module.id.clone(),
+                message,
+                code: Some(ErrorCode::SelinuxContext),
+                path: None,
+            });
+        }
+    }
+
+    issues
+}
+
+// Compares the label of every file a module ships with the one it should carry
+// once mounted: the module's own override, else the label of the system file it
+// shadows, else the device file_contexts. Only synced copies under
+// `storage_root` are what overlayfs serves, so only those escalate to critical.
+pub fn diagnose_labels(
+    plan: &MountPlan,
+    modules: &[Module],
+    sysroot: &Path,
+    storage_root: Option<&Path>,
+) -> Vec<DiagnosticIssue> {
+    let device = FileContexts::load(sysroot);
+
+    let overrides: HashMap<&str, FileContexts> = modules
+        .iter()
+        .map(|m| {
+            (
+                m.id.as_str(),
+                FileContexts::for_module(&m.source_path, &m.rules),
+            )
+        })
+        .collect();
+
+    // (module id, layer root, path of the layer root on the device)
+    let mut layers: Vec<(String, PathBuf, PathBuf)> = Vec::new();
+
+    for op in &plan.overlay_ops {
+        for lower in &op.lowerdirs {
+            let mod_id = extract_id(lower).unwrap_or_else(|| "unknown".into());
+
+            layers.push((
+                mod_id,
+                lower.clone(),
+                Path::new("/").join(&op.partition_name),
+            ));
+        }
+    }
+
+    for module_path in &plan.magic_module_paths {
+        let mod_id = module_path
+            .file_name()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_else(|| "unknown".into());
+
+        for part in defs::BUILTIN_PARTITIONS {
+            layers.push((
+                mod_id.clone(),
+                module_path.join(part),
+                Path::new("/").join(part),
+            ));
+        }
+    }
+
+    // Module id and the issue, or None where the label could not be read.
+    let findings: Vec<(String, Option<DiagnosticIssue>)> = layers
+        .par_iter()
+        .flat_map_iter(|(mod_id, layer, device_root)| {
+            let overrides = overrides.get(mod_id.as_str());
+
+            let device = &device;
+
+            let synced = storage_root.is_some_and(|root| layer.starts_with(root));
+
+            WalkDir::new(layer)
+                .min_depth(1)
+                .into_iter()
+                .flatten()
+                .filter(|entry| entry.file_type().is_file() || entry.path_is_symlink())
+                .filter(|entry| entry.file_name() != defs::REPLACE_DIR_FILE_NAME)
+                .filter_map(move |entry| {
+                    let relative = entry.path().strip_prefix(layer).ok()?;
+
+                    let device_path = device_root.join(relative);
+
+                    let expected = expected_label(
+                        &device_path,
+                        entry.file_type(),
+                        overrides,
+                        device,
+                        sysroot,
+                    )?;
+
+                    // Hosts without SELinux have nothing to compare against.
+                    let Some(actual) = utils::lgetfilecon(entry.path())
+                        .ok()
+                        .filter(|context| !context.is_empty())
+                    else {
+                        return Some((mod_id.clone(), None));
+                    };
+
+                    if actual == expected {
+                        return None;
+                    }
+
+                    let level = if synced && is_binary(&device_path, entry.path()) {
+                        DiagnosticLevel::Critical
+                    } else {
+                        DiagnosticLevel::Warning
+                    };
+
+                    Some((
+                        mod_id.clone(),
+                        Some(DiagnosticIssue {
+                            level,
+                            context: mod_id.clone(),
+                            message: format!(
+                                "Label mismatch on {}: {} (expected {})",
+                                device_path.display(),
+                                actual,
+                                expected
+                            ),
+                            code: Some(ErrorCode::SelinuxContext),
+                            path: Some(device_path.clone()),
+                        }),
+                    ))
+                })
+        })
+        .collect();
+
+    let mut unreadable: BTreeMap<String, usize> = BTreeMap::new();
+
+    let mut issues = Vec::new();
+
+    for (mod_id, issue) in findings {
+        match issue {
+            Some(issue) => issues.push(issue),
+            None => *unreadable.entry(mod_id).or_default() += 1,
+        }
+    }
+
+    issues.extend(
+        unreadable
+            .into_iter()
+            .map(|(mod_id, count)| DiagnosticIssue {
+                level: DiagnosticLevel::Info,
+                message: format!("Labels of {} files could not be read, audit skipped", count),
+                context: mod_id,
+                code: Some(ErrorCode::SelinuxContext),
+                path: None,
+            }),
+    );
+
+    issues
+}
+
+fn expected_label(
+    device_path: &Path,
+    file_type: std::fs::FileType,
+    overrides: Option<&FileContexts>,
+    device: &FileContexts,
+    sysroot: &Path,
+) -> Option<String> {
+    if let Some(context) = overrides.and_then(|o| o.lookup(device_path, file_type)) {
+        return Some(context.to_string());
+    }
+
+    let shadowed = utils::under_root(sysroot, device_path);
+
+    if shadowed.symlink_metadata().is_ok()
+        && let Ok(context) = utils::lgetfilecon(&shadowed)
+    {
+        return Some(context);
+    }
+
+    device.lookup(device_path, file_type).map(str::to_string)
+}
+
+// Executables and shared libraries under bin, lib or lib64 fail to load or run
+// when mislabeled, which tends to take the boot down with them.
+fn is_binary(device_path: &Path, path: &Path) -> bool {
+    let in_binary_dir = device_path
+        .parent()
+        .into_iter()
+        .flat_map(|p| p.components())
+        .any(|c| matches!(c.as_os_str().to_str(), Some("bin" | "lib" | "lib64")));
+
+    if !in_binary_dir {
+        return false;
+    }
+
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    name.ends_with(".so")
+        || name.contains(".so.")
+        || std::fs::metadata(path).is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+}
+
+pub fn rw_layers(partition_name: &str) -> (Option<PathBuf>, Option<PathBuf>) {
+    let part_rw = Path::new(defs::SYSTEM_RW_DIR).join(partition_name);
+
+    let upper = part_rw.join("upperdir");
+
+    let work = part_rw.join("workdir");
+
+    if upper.exists() && work.exists() {
+        (Some(upper), Some(work))
+    } else {
+        (None, None)
+    }
+}
+
+pub fn apply_forced_selections(
+    selections: &[ForcedSelection],
+    mounted_partitions: &HashSet<String>,
+    disable_umount: bool,
+    backend: &dyn MountBackend,
+) {
+    for sel in selections {
+        if !mounted_partitions.contains(&sel.partition_name) {
+            log::warn!(
+                "Winnowing: {} not mounted via OverlayFS, cannot pin {}",
+                sel.partition_name,
+                sel.target.display()
+            );
+
+            continue;
+        }
+
+        log::info!(
+            "Winnowing: pinning {} to module {}",
+            sel.target.display(),
+            sel.module_id
+        );
+
+        if let Err(e) = backend.bind_readonly(&sel.source, &sel.target) {
+            log::warn!("Failed to pin {}: {:#}", sel.target.display(), e);
+
+            continue;
+        }
+
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if !disable_umount {
+            let _ = send_unmountable(&sel.target);
+        }
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    let _ = disable_umount;
+}
+
+pub fn execute(
+    plan: &MountPlan,
+    config: &config::Config,
+    backend: &dyn MountBackend,
+) -> Result<ExecutionResult> {
+    let checkpoint = backend.checkpoint();
+
+    let outcome = mount_all(plan, config, backend);
+
+    if !config.strict_mount {
+        return outcome;
+    }
+
+    let failure = match &outcome {
+        Ok(result) => result.magic_error.as_ref().map(|e| e.message.clone()),
+        Err(e) => Some(format!("{:#}", e)),
+    };
+
+    let Some(failure) = failure else {
+        return outcome;
+    };
+
+    log::error!(
+        ">> Strict mode: rolling back every mount of this run ({})",
+        failure
+    );
+
+    let report = backend.rollback(checkpoint);
+
+    for failed in &report.failed {
+        log::error!(
+            "Rollback could not detach {:?} {}: {}",
+            failed.kind,
+            failed.target.display(),
+            failed.error
+        );
+    }
+
+    match outcome {
+        Ok(mut result) => {
+            result.overlay_module_ids.clear();
+
+            result.magic_module_ids.clear();
+
+            result.magic_paths.clear();
+
+            result.rollback = Some(report);
+
+            Ok(result)
+        }
+        Err(e) => Err(e.context(format!(
+            "strict mode rolled back {} mounts",
+            report.detached.len()
+        ))),
+    }
+}
+
+fn mount_all(
+    plan: &MountPlan,
+    config: &config::Config,
+    backend: &dyn MountBackend,
+) -> Result<ExecutionResult> {
     let mut magic_queue = plan.magic_module_paths.clone();
 
     let mut global_success_map: HashMap<PathBuf, HashSet<String>> = HashMap::new();
@@ -168,7 +523,7 @@ pub fn execute(plan: &MountPlan, config: &config::Config) -> Result<ExecutionRes
         final_overlay_ids.insert(id.clone());
     });
 
-    repair_rw_contexts();
+    repair_rw_contexts(&config.sysroot, backend);
 
     log::info!(">> Phase 2: OverlayFS Execution...");
 
@@ -182,19 +537,7 @@ pub fn execute(plan: &MountPlan, config: &config::Config) -> Result<ExecutionRes
                 .map(|p: &PathBuf| p.display().to_string())
                 .collect();
 
-            let rw_root = Path::new(defs::SYSTEM_RW_DIR);
-
-            let part_rw = rw_root.join(&op.partition_name);
-
-            let upper = part_rw.join("upperdir");
-
-            let work = part_rw.join("workdir");
-
-            let (upper_opt, work_opt) = if upper.exists() && work.exists() {
-                (Some(upper), Some(work))
-            } else {
-                (None, None)
-            };
+            let (upper_opt, work_opt) = rw_layers(&op.partition_name);
 
             log::info!(
                 "Mounting {} [OVERLAY] (Layers: {})",
@@ -202,7 +545,7 @@ pub fn execute(plan: &MountPlan, config: &config::Config) -> Result<ExecutionRes
                 lowerdir_strings.len()
             );
 
-            if let Err(e) = overlay::mount_overlay(
+            if let Err(e) = backend.mount_overlay(
                 &op.target,
                 &lowerdir_strings,
                 work_opt,
@@ -215,6 +558,13 @@ pub fn execute(plan: &MountPlan, config: &config::Config) -> Result<ExecutionRes
                     e
                 );
 
+                let error =
+                    ErrorReport::new(op.target.clone(), &e).with_default(ErrorCode::OverlayMount);
+
+                if let Some(result) = retry_without_culprits(op, &error, config, backend) {
+                    return result;
+                }
+
                 let mut local_magic = Vec::new();
 
                 let mut local_fallback_ids = Vec::new();
@@ -231,8 +581,15 @@ pub fn execute(plan: &MountPlan, config: &config::Config) -> Result<ExecutionRes
 
                 return OverlayResult {
                     magic_roots: local_magic,
-                    fallback_ids: local_fallback_ids,
+                    fallback: Some(OverlayFallback {
+                        partition_name: op.partition_name.clone(),
+                        target: op.target.clone(),
+                        module_ids: local_fallback_ids,
+                        error,
+                        partial: false,
+                    }),
                     success_records: Vec::new(),
+                    culprits: Vec::new(),
                 };
             }
 
@@ -246,20 +603,35 @@ pub fn execute(plan: &MountPlan, config: &config::Config) -> Result<ExecutionRes
 
             OverlayResult {
                 magic_roots: Vec::new(),
-                fallback_ids: Vec::new(),
+                fallback: None,
                 success_records: successes,
+                culprits: Vec::new(),
             }
         })
         .collect();
 
+    let mut mounted_partitions = HashSet::new();
+
+    let mut fallbacks = Vec::new();
+
+    let mut culprits = Vec::new();
+
     for res in overlay_results {
         magic_queue.extend(res.magic_roots);
 
-        for id in res.fallback_ids {
-            final_overlay_ids.remove(&id);
+        culprits.extend(res.culprits);
+
+        if let Some(fallback) = res.fallback {
+            for id in &fallback.module_ids {
+                final_overlay_ids.remove(id);
+            }
+
+            fallbacks.push(fallback);
         }
 
         for (root, partition) in res.success_records {
+            mounted_partitions.insert(partition.clone());
+
             global_success_map
                 .entry(root)
                 .or_default()
@@ -267,14 +639,31 @@ pub fn execute(plan: &MountPlan, config: &config::Config) -> Result<ExecutionRes
         }
     }
 
-    magic_queue.sort();
+    apply_forced_selections(
+        &plan.forced_selections,
+        &mounted_partitions,
+        config.disable_umount,
+        backend,
+    );
+
+    magic_queue.sort_by_key(|path| {
+        path.file_name()
+            .map(|name| plan.precedence_of(&name.to_string_lossy()))
+            .unwrap_or(usize::MAX)
+    });
+
+    let mut seen = HashSet::new();
 
-    magic_queue.dedup();
+    magic_queue.retain(|path| seen.insert(path.clone()));
 
     let mut final_magic_ids = Vec::new();
 
+    let mut magic_error = None;
+
+    let mut magic_exclusions = HashMap::new();
+
     if !magic_queue.is_empty() {
-        let tempdir = utils::select_temp_dir()?;
+        let tempdir = backend.temp_dir()?;
 
         for path in &magic_queue {
             if let Some(name) = path.file_name() {
@@ -287,26 +676,34 @@ pub fn execute(plan: &MountPlan, config: &config::Config) -> Result<ExecutionRes
             tempdir.display()
         );
 
-        if !tempdir.exists() {
-            std::fs::create_dir_all(&tempdir)?;
-        }
+        backend.mount_tmpfs(&tempdir, "tmpfs")?;
+
+        let request = MagicRequest {
+            tmp_path: &tempdir,
+            module_paths: &magic_queue,
+            sysroot: &config.sysroot,
+            mount_source: &config.mountsource,
+            extra_partitions: &config.partitions,
+            exclusions: global_success_map,
+            module_rules: &plan.magic_rules,
+            disable_umount: config.disable_umount,
+        };
+
+        match backend.magic_mount(&request) {
+            Ok(()) => magic_exclusions = request.exclusions,
+            Err(e) => {
+                log::error!("Magic Mount critical failure: {:#}", e);
 
-        utils::mount_tmpfs(&tempdir, "tmpfs")?;
+                final_magic_ids.clear();
 
-        if let Err(e) = magic::mount_partitions(
-            &tempdir,
-            &magic_queue,
-            &config.mountsource,
-            &config.partitions,
-            global_success_map,
-            config.disable_umount,
-        ) {
-            log::error!("Magic Mount critical failure: {:#}", e);
+                magic_queue.clear();
 
-            final_magic_ids.clear();
+                magic_error =
+                    Some(ErrorReport::new("magic", &e).with_default(ErrorCode::MagicMount));
+            }
         }
 
-        let _ = rustix::mount::unmount(&tempdir, UnmountFlags::DETACH);
+        let _ = backend.unmount(&tempdir);
     }
 
     let mut result_overlay = final_overlay_ids.into_iter().collect::<Vec<_>>();
@@ -322,5 +719,307 @@ pub fn execute(plan: &MountPlan, config: &config::Config) -> Result<ExecutionRes
     Ok(ExecutionResult {
         overlay_module_ids: result_overlay,
         magic_module_ids: result_magic,
+        fallbacks,
+        culprits,
+        magic_error,
+        magic_paths: magic_queue,
+        magic_exclusions,
+        rollback: None,
     })
 }
+
+// Bisects the layers of a failed overlay to find the ones that break it, then
+// mounts the rest so only the culprits fall back to magic mount. Gives up when
+// the failure cannot be pinned on individual layers.
+fn retry_without_culprits(
+    op: &OverlayOperation,
+    error: &ErrorReport,
+    config: &config::Config,
+    backend: &dyn MountBackend,
+) -> Option<OverlayResult> {
+    if op.lowerdirs.len() < 2 || error.code == ErrorCode::LowerdirTooLong {
+        return None;
+    }
+
+    let mut accepted = HashSet::new();
+
+    let culprits = bisect_layers(
+        backend,
+        &op.target,
+        &op.lowerdirs,
+        &op.lowerdirs,
+        &mut accepted,
+    );
+
+    if culprits.is_empty() || accepted.is_empty() {
+        return None;
+    }
+
+    let remaining: Vec<&PathBuf> = op
+        .lowerdirs
+        .iter()
+        .filter(|layer| accepted.contains(*layer))
+        .collect();
+
+    let remaining_strings: Vec<String> =
+        remaining.iter().map(|p| p.display().to_string()).collect();
+
+    let (upper_opt, work_opt) = rw_layers(&op.partition_name);
+
+    if let Err(e) = backend.mount_overlay(
+        &op.target,
+        &remaining_strings,
+        work_opt,
+        upper_opt,
+        config.disable_umount,
+    ) {
+        log::warn!(
+            "OverlayFS still failed for {} without the offending layers: {:#}",
+            op.target,
+            e
+        );
+
+        return None;
+    }
+
+    let culprit_ids: Vec<String> = culprits
+        .iter()
+        .filter_map(|(layer, _)| extract_id(layer))
+        .collect();
+
+    log::warn!(
+        "Mounted {} without {:?}, sending only those to Magic Mount",
+        op.target,
+        culprit_ids
+    );
+
+    Some(OverlayResult {
+        magic_roots: culprits
+            .iter()
+            .filter_map(|(layer, _)| extract_module_root(layer))
+            .collect(),
+        fallback: Some(OverlayFallback {
+            partition_name: op.partition_name.clone(),
+            target: op.target.clone(),
+            module_ids: culprit_ids,
+            error: error.clone(),
+            partial: true,
+        }),
+        success_records: remaining
+            .iter()
+            .filter_map(|layer| extract_module_root(layer))
+            .map(|root| (root, op.partition_name.clone()))
+            .collect(),
+        culprits: culprits
+            .into_iter()
+            .map(|(layer, e)| OverlayCulprit {
+                module_id: extract_id(&layer).unwrap_or_else(|| "unknown".into()),
+                partition: op.partition_name.clone(),
+                error: ErrorReport::new(op.target.clone(), &e)
+                    .with_default(ErrorCode::OverlayMount),
+            })
+            .collect(),
+    })
+}
+
+// Layers that stack with everything accepted so far are accepted as a group;
+// otherwise the group is halved until single offending layers remain.
+fn bisect_layers(
+    backend: &dyn MountBackend,
+    target: &str,
+    all: &[PathBuf],
+    candidates: &[PathBuf],
+    accepted: &mut HashSet<PathBuf>,
+) -> Vec<(PathBuf, anyhow::Error)> {
+    if candidates.is_empty() {
+        return Vec::new();
+    }
+
+    // Precedence has to stay as planned, so trials keep the original order.
+    let trial: Vec<String> = all
+        .iter()
+        .filter(|layer| accepted.contains(*layer) || candidates.contains(layer))
+        .map(|layer| layer.display().to_string())
+        .collect();
+
+    let e = match backend.probe_overlay(target, &trial) {
+        Ok(()) => {
+            accepted.extend(candidates.iter().cloned());
+
+            return Vec::new();
+        }
+        Err(e) => e,
+    };
+
+    if let [layer] = candidates {
+        return vec![(layer.clone(), e)];
+    }
+
+    let (left, right) = candidates.split_at(candidates.len() / 2);
+
+    let mut culprits = bisect_layers(backend, target, all, left, accepted);
+
+    culprits.extend(bisect_layers(backend, target, all, right, accepted));
+
+    culprits
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix::fs::PermissionsExt, path::Path};
+
+    use anyhow::{bail, ensure};
+
+    use super::{DiagnosticLevel, diagnose_contexts, diagnose_labels};
+    use crate::{
+        core::{contexts::FileContexts, inventory, planner, tests::simulated},
+        utils,
+    };
+
+    #[test]
+    fn module_context_overrides_are_validated() {
+        simulated("module_context_overrides_are_validated", |fx| {
+            fx.system_file(
+            "system/etc/selinux/plat_file_contexts",
+            "/system(/.*)?  u:object_r:system_file:s0\n/system/bin/tool  u:object_r:tool_exec:s0\n",
+        )?;
+
+            fx.module_file("alpha", "system/bin/tool", "tool")?;
+
+            fx.module_file(
+            "alpha",
+            "file_contexts",
+            "/system/bin/tool  u:object_r:tool_exec:s0\n/system/bin/.*  u:object_r:bogus_exec:s0\n/system/bin/oops  u:object_r\n",
+        )?;
+
+            fx.module_rules(
+                "alpha",
+                r#"{"contexts": {"/system/etc/missing": "u:object_r:system_file:s0"}}"#,
+            )?;
+
+            let modules = inventory::scan(&fx.moduledir, &fx.config())?;
+
+            let overrides = FileContexts::for_module(&modules[0].source_path, &modules[0].rules);
+
+            let file =
+                fs::symlink_metadata(fx.moduledir.join("alpha/system/bin/tool"))?.file_type();
+
+            // Literal entries win over patterns, just like the device file_contexts.
+            ensure!(
+                overrides.lookup(Path::new("/system/bin/tool"), file)
+                    == Some("u:object_r:tool_exec:s0")
+            );
+
+            let issues = diagnose_contexts(&modules, &fx.sysroot);
+
+            let levels: Vec<(&str, &str)> = issues
+                .iter()
+                .map(|issue| {
+                    let level = match issue.level {
+                        DiagnosticLevel::Info => "info",
+                        DiagnosticLevel::Warning => "warning",
+                        DiagnosticLevel::Critical => "critical",
+                    };
+
+                    (level, issue.message.as_str())
+                })
+                .collect();
+
+            ensure!(levels.len() == 3, "unexpected issues: {:?}", levels);
+
+            ensure!(levels[0].0 == "warning" && levels[0].1.contains("bogus_exec"));
+
+            ensure!(levels[1].0 == "critical" && levels[1].1.contains("/system/bin/oops"));
+
+            ensure!(levels[2].0 == "info" && levels[2].1.contains("/system/etc/missing"));
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn label_audit_never_escalates_unsynced_files() {
+        simulated("label_audit_never_escalates_unsynced_files", |fx| {
+            fx.system_file(
+            "system/etc/selinux/plat_file_contexts",
+            "/system(/.*)?  u:object_r:system_file:s0\n/system/bin/tool  u:object_r:tool_exec:s0\n",
+        )?;
+
+            fx.system_file("system/etc/hosts", "stock")?;
+
+            fx.module_file("alpha", "system/bin/tool", "tool")?;
+
+            fs::set_permissions(
+                fx.moduledir.join("alpha/system/bin/tool"),
+                fs::Permissions::from_mode(0o755),
+            )?;
+
+            fx.module_file("alpha", "system/etc/hosts", "alpha")?;
+
+            fx.module_file("alpha", "vendor/etc/unlisted", "alpha")?;
+
+            fx.module_file("beta", "system/lib64/libbeta.so", "beta")?;
+
+            fx.module_rules("beta", r#"{"default_mode": "magic"}"#)?;
+
+            let config = fx.config();
+
+            let modules = inventory::scan(&fx.moduledir, &config)?;
+
+            let plan = planner::generate(&config, &modules, &fx.moduledir)?;
+
+            let issues = diagnose_labels(&plan, &modules, &fx.sysroot, None);
+
+            // The module dir is not what gets mounted, so nothing in it is critical.
+            ensure!(
+                !issues
+                    .iter()
+                    .any(|issue| matches!(issue.level, DiagnosticLevel::Critical))
+            );
+
+            let mut contexts: Vec<&str> = issues.iter().map(|i| i.context.as_str()).collect();
+
+            contexts.sort();
+
+            contexts.dedup();
+
+            ensure!(
+                contexts == ["alpha", "beta"],
+                "unexpected findings: {:?}",
+                contexts
+            );
+
+            // Mismatches name the device path, the per-module notes name none.
+            let shipped = [
+                "/system/bin/tool",
+                "/system/etc/hosts",
+                "/vendor/etc/unlisted",
+                "/system/lib64/libbeta.so",
+            ];
+
+            for issue in &issues {
+                match (&issue.level, &issue.path) {
+                    (DiagnosticLevel::Info, path) => ensure!(path.is_none()),
+                    (_, Some(path)) => ensure!(
+                        shipped.iter().any(|p| Path::new(p) == path),
+                        "unexpected path {}",
+                        path.display()
+                    ),
+                    (_, None) => bail!("mismatch without a path: {}", issue.message),
+                }
+            }
+
+            // Without readable labels each module gets a single note instead.
+            if utils::lgetfilecon(fx.moduledir.join("alpha/system/bin/tool")).is_err() {
+                ensure!(
+                    issues.len() == 2
+                        && issues
+                            .iter()
+                            .all(|issue| matches!(issue.level, DiagnosticLevel::Info))
+                );
+            }
+
+            Ok(())
+        });
+    }
+}
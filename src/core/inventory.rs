@@ -2,16 +2,22 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
 use std::{
-    collections::HashMap,
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap},
     fs,
     path::{Path, PathBuf},
+    sync::OnceLock,
 };
 
 use anyhow::Result;
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 
-use crate::{conf::config, defs};
+use crate::{
+    conf::{config, pattern},
+    core::triage::{self, ModeDecision},
+    defs,
+};
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
 #[serde(rename_all = "lowercase")]
@@ -20,6 +26,7 @@ pub enum MountMode {
     Overlay,
     Magic,
     Ignore,
+    Auto,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
@@ -28,6 +35,15 @@ pub struct ModuleRules {
     pub default_mode: MountMode,
     #[serde(default)]
     pub paths: HashMap<String, MountMode>,
+    // file_contexts path regex -> SELinux context, overriding every heuristic.
+    #[serde(default)]
+    pub contexts: BTreeMap<String, String>,
+    // What `auto` resolved to for this boot. Never written back to rule files.
+    #[serde(skip)]
+    pub auto_mode: Option<MountMode>,
+    // The same for each `auto` entry of `paths`, keyed by pattern.
+    #[serde(skip)]
+    pub auto_paths: HashMap<String, MountMode>,
 }
 
 impl ModuleRules {
@@ -57,6 +73,8 @@ impl ModuleRules {
                         rules.default_mode = user_rules.default_mode;
 
                         rules.paths.extend(user_rules.paths);
+
+                        rules.contexts.extend(user_rules.contexts);
                     }
                     Err(e) => log::warn!("Failed to parse user rules for '{}': {}", module_id, e),
                 },
@@ -67,29 +85,122 @@ impl ModuleRules {
         rules
     }
 
-    pub fn get_mode(&self, relative_path: &str) -> MountMode {
-        if let Some(mode) = self.paths.get(relative_path) {
-            return mode.clone();
+    fn auto_patterns(&self) -> Vec<String> {
+        let mut patterns: Vec<String> = self
+            .paths
+            .iter()
+            .filter(|(_, m)| **m == MountMode::Auto)
+            .map(|(p, _)| p.clone())
+            .collect();
+
+        patterns.sort();
+
+        patterns
+    }
+
+    fn resolve(&self, pattern: Option<&str>, mode: &MountMode) -> MountMode {
+        match mode {
+            MountMode::Auto => pattern
+                .and_then(|p| self.auto_paths.get(p))
+                .or(self.auto_mode.as_ref())
+                .cloned()
+                .unwrap_or_default(),
+            other => other.clone(),
+        }
+    }
+
+    pub fn effective_default(&self) -> MountMode {
+        self.resolve(None, &self.default_mode)
+    }
+
+    pub fn get_mode(&self, relative_path: &str) -> ModeMatch<'_> {
+        match pattern::best_match(&self.paths, |p| pattern::match_prefix(p, relative_path)) {
+            Some((pattern, mode)) => ModeMatch {
+                mode: self.resolve(Some(pattern), mode),
+                pattern: Some(pattern),
+            },
+            None => ModeMatch {
+                mode: self.effective_default(),
+                pattern: None,
+            },
         }
+    }
+
+    pub fn has_mode_under(&self, relative_path: &str, mode: &MountMode) -> bool {
+        self.paths.iter().any(|(p, m)| {
+            self.resolve(Some(p), m) == *mode && pattern::may_match_below(p, relative_path)
+        })
+    }
 
-        self.default_mode.clone()
+    pub fn wants_mode(&self, mode: &MountMode) -> bool {
+        self.effective_default() == *mode
+            || self
+                .paths
+                .iter()
+                .any(|(p, m)| self.resolve(Some(p), m) == *mode)
+    }
+
+    // The same selection with every OverlayFS pick handed to Magic Mount.
+    pub fn overlay_as_magic(&self) -> Self {
+        let swap = |mode: MountMode| match mode {
+            MountMode::Overlay => MountMode::Magic,
+            other => other,
+        };
+
+        Self {
+            default_mode: swap(self.default_mode.clone()),
+            paths: self
+                .paths
+                .iter()
+                .map(|(p, m)| (p.clone(), swap(m.clone())))
+                .collect(),
+            contexts: self.contexts.clone(),
+            auto_mode: Some(swap(self.resolve(None, &MountMode::Auto))),
+            auto_paths: self
+                .auto_patterns()
+                .into_iter()
+                .map(|p| {
+                    let mode = swap(self.resolve(Some(&p), &MountMode::Auto));
+
+                    (p, mode)
+                })
+                .collect(),
+        }
+    }
+
+    pub fn selects(&self, relative_path: &str, is_dir: bool, mode: &MountMode) -> bool {
+        if self.get_mode(relative_path).mode == *mode {
+            return true;
+        }
+
+        is_dir && self.has_mode_under(relative_path, mode)
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModeMatch<'a> {
+    pub mode: MountMode,
+    pub pattern: Option<&'a str>,
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct Module {
     pub id: String,
     pub source_path: PathBuf,
     pub rules: ModuleRules,
+    pub decision: ModeDecision,
 }
 
-pub fn scan(source_dir: &Path, _config: &config::Config) -> Result<Vec<Module>> {
+pub fn scan(source_dir: &Path, config: &config::Config) -> Result<Vec<Module>> {
     if !source_dir.exists() {
         return Ok(Vec::new());
     }
 
     let dir_entries = fs::read_dir(source_dir)?.collect::<std::io::Result<Vec<_>>>()?;
 
+    // Reading /proc/mounts is only needed once a module asks for `auto`.
+    let mounts = OnceLock::new();
+
     let mut modules: Vec<Module> = dir_entries
         .into_par_iter()
         .filter_map(|entry| {
@@ -115,17 +226,67 @@ pub fn scan(source_dir: &Path, _config: &config::Config) -> Result<Vec<Module>>
                 return None;
             }
 
-            let rules = ModuleRules::load(&path, &id);
+            let mut rules = ModuleRules::load(&path, &id);
+
+            let mounts = || mounts.get_or_init(|| triage::child_mounts(config));
+
+            let mut decision = if rules.default_mode == MountMode::Auto {
+                let decision = triage::select_mode(&path, config, mounts(), None);
+
+                log::info!(
+                    "Module {} mounts via {:?} ({})",
+                    id,
+                    decision.mode,
+                    decision.reason
+                );
+
+                rules.auto_mode = Some(decision.mode.clone());
+
+                decision
+            } else {
+                ModeDecision {
+                    mode: rules.default_mode.clone(),
+                    reason: "default".to_string(),
+                    ..Default::default()
+                }
+            };
+
+            // Each auto pattern only answers for the subtree it covers.
+            for pattern in rules.auto_patterns() {
+                let path_decision = triage::select_mode(&path, config, mounts(), Some(&pattern));
+
+                log::info!(
+                    "Module {} mounts {} via {:?} ({})",
+                    id,
+                    pattern,
+                    path_decision.mode,
+                    path_decision.reason
+                );
+
+                rules
+                    .auto_paths
+                    .insert(pattern.clone(), path_decision.mode.clone());
+
+                decision.paths.insert(pattern, path_decision);
+            }
 
             Some(Module {
                 id,
                 source_path: path,
                 rules,
+                decision,
             })
         })
         .collect();
 
-    modules.sort_by(|a, b| b.id.cmp(&a.id));
+    let rank = |id: &str| config.priority.iter().position(|p| p == id);
+
+    modules.sort_by(|a, b| match (rank(&a.id), rank(&b.id)) {
+        (Some(x), Some(y)) => x.cmp(&y),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => b.id.cmp(&a.id),
+    });
 
     Ok(modules)
 }
@@ -0,0 +1,288 @@
+// Copyright 2025 Meta-Hybrid Mount Authors
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    core::{
+        executor::{DiagnosticIssue, DiagnosticLevel, ExecutionResult},
+        planner::{ForcedSelection, MountPlan, OverlayOperation},
+    },
+    defs,
+    error::ErrorReport,
+    mount::journal::{self, MountKind, MountRecord},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverlayStatus {
+    Mounted,
+    Fallback,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OverlayEntry {
+    pub partition: String,
+    pub target: String,
+    pub lowerdirs: Vec<String>,
+    #[serde(default)]
+    pub child_mounts: Vec<PathBuf>,
+    pub status: OverlayStatus,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FallbackEntry {
+    pub partition: String,
+    pub target: String,
+    pub modules: Vec<String>,
+    pub error: ErrorReport,
+    #[serde(default)]
+    pub partial: bool,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ExecutionManifest {
+    pub timestamp: u64,
+    pub storage_mode: String,
+    pub storage_root: PathBuf,
+    pub overlays: Vec<OverlayEntry>,
+    pub fallbacks: Vec<FallbackEntry>,
+    pub magic_modules: Vec<String>,
+    pub magic_paths: Vec<PathBuf>,
+    pub magic_error: Option<ErrorReport>,
+    pub pins: Vec<ForcedSelection>,
+    pub mounts: Vec<MountRecord>,
+}
+
+impl OverlayEntry {
+    pub fn module_ids(&self) -> impl Iterator<Item = String> + '_ {
+        self.lowerdirs
+            .iter()
+            .map(|l| OverlayOperation::layer_module_id(Path::new(l)))
+    }
+}
+
+fn children_of(mounts: &[MountRecord], target: &str) -> Vec<PathBuf> {
+    mounts
+        .iter()
+        .filter(|r| r.kind == MountKind::ChildRestore && r.target.starts_with(target))
+        .map(|r| r.target.clone())
+        .collect()
+}
+
+impl ExecutionManifest {
+    pub fn build(
+        storage_mode: &str,
+        storage_root: &Path,
+        plan: &MountPlan,
+        result: &ExecutionResult,
+    ) -> Self {
+        let mounts = journal::snapshot();
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        // After a rollback none of the planned overlays are in place.
+        let planned_ops = match result.rollback {
+            Some(_) => &[][..],
+            None => &plan.overlay_ops[..],
+        };
+
+        let overlays = planned_ops
+            .iter()
+            .map(|op| {
+                let fallback = result
+                    .fallbacks
+                    .iter()
+                    .find(|f| f.partition_name == op.partition_name);
+
+                // A partial fallback mounted the overlay without the culprits.
+                let dropped = |layer: &Path| {
+                    fallback.is_some_and(|f| {
+                        f.partial
+                            && f.module_ids
+                                .contains(&OverlayOperation::layer_module_id(layer))
+                    })
+                };
+
+                OverlayEntry {
+                    partition: op.partition_name.clone(),
+                    target: op.target.clone(),
+                    lowerdirs: op
+                        .lowerdirs
+                        .iter()
+                        .filter(|p| !dropped(p))
+                        .map(|p| p.display().to_string())
+                        .collect(),
+                    child_mounts: children_of(&mounts, &op.target),
+                    status: match fallback {
+                        Some(f) if !f.partial => OverlayStatus::Fallback,
+                        _ => OverlayStatus::Mounted,
+                    },
+                }
+            })
+            .collect();
+
+        let fallbacks = result
+            .fallbacks
+            .iter()
+            .map(|f| FallbackEntry {
+                partition: f.partition_name.clone(),
+                target: f.target.clone(),
+                modules: f.module_ids.clone(),
+                error: f.error.clone(),
+                partial: f.partial,
+            })
+            .collect();
+
+        let magic_paths = mounts
+            .iter()
+            .filter(|r| matches!(r.kind, MountKind::MagicFile | MountKind::MagicSkeleton))
+            .map(|r| r.target.clone())
+            .collect();
+
+        let pins = plan
+            .forced_selections
+            .iter()
+            .filter(|sel| {
+                mounts
+                    .iter()
+                    .any(|r| r.kind == MountKind::WinnowPin && r.target == sel.target)
+            })
+            .cloned()
+            .collect();
+
+        Self {
+            timestamp,
+            storage_mode: storage_mode.to_string(),
+            storage_root: storage_root.to_path_buf(),
+            overlays,
+            fallbacks,
+            magic_modules: result.magic_module_ids.clone(),
+            magic_paths,
+            magic_error: result.magic_error.clone(),
+            pins,
+            mounts,
+        }
+    }
+
+    // A boot that failed before finalize has no plan to describe, only whatever
+    // the journal still holds.
+    pub fn from_journal() -> Self {
+        let mounts = journal::snapshot();
+
+        let storage_root = mounts
+            .iter()
+            .find(|r| r.kind == MountKind::Storage)
+            .map(|r| r.target.clone())
+            .unwrap_or_default();
+
+        let magic_paths = mounts
+            .iter()
+            .filter(|r| matches!(r.kind, MountKind::MagicFile | MountKind::MagicSkeleton))
+            .map(|r| r.target.clone())
+            .collect();
+
+        Self {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            storage_root,
+            magic_paths,
+            mounts,
+            ..Self::default()
+        }
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+
+        fs::write(defs::MANIFEST_FILE, json)?;
+
+        Ok(())
+    }
+
+    pub fn load() -> Result<Self> {
+        if !Path::new(defs::MANIFEST_FILE).exists() {
+            return Ok(Self::default());
+        }
+
+        let content = fs::read_to_string(defs::MANIFEST_FILE)?;
+
+        let manifest = serde_json::from_str(&content)?;
+
+        Ok(manifest)
+    }
+
+    pub fn overlay_partitions_of(&self, module_id: &str) -> Vec<String> {
+        self.overlays
+            .iter()
+            .filter(|o| {
+                o.status == OverlayStatus::Mounted && o.module_ids().any(|id| id == module_id)
+            })
+            .map(|o| o.partition.clone())
+            .collect()
+    }
+
+    pub fn fallback_of(&self, module_id: &str) -> Option<&FallbackEntry> {
+        self.fallbacks
+            .iter()
+            .find(|f| f.modules.iter().any(|id| id == module_id))
+    }
+
+    pub fn diagnose(&self) -> Vec<DiagnosticIssue> {
+        let mut issues = Vec::new();
+
+        for fallback in &self.fallbacks {
+            issues.push(DiagnosticIssue {
+                level: DiagnosticLevel::Warning,
+                context: fallback.partition.clone(),
+                message: format!(
+                    "OverlayFS on {} fell back to Magic Mount for {:?}: {}",
+                    fallback.target, fallback.modules, fallback.error.message
+                ),
+                code: Some(fallback.error.code),
+                path: None,
+            });
+        }
+
+        if let Some(error) = &self.magic_error {
+            issues.push(DiagnosticIssue {
+                level: DiagnosticLevel::Warning,
+                context: "magic".to_string(),
+                message: format!("Magic Mount failed at boot: {}", error.message),
+                code: Some(error.code),
+                path: None,
+            });
+        }
+
+        for record in &self.mounts {
+            if !journal::is_live(record) {
+                issues.push(DiagnosticIssue {
+                    level: DiagnosticLevel::Warning,
+                    context: "mounts".to_string(),
+                    message: format!(
+                        "Recorded {:?} mount is no longer visible at {}",
+                        record.kind,
+                        record.target.display()
+                    ),
+                    code: None,
+                    path: Some(record.target.clone()),
+                });
+            }
+        }
+
+        issues
+    }
+}
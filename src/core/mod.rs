@@ -1,21 +1,34 @@
 // Copyright 2025 Meta-Hybrid Mount Authors
 // SPDX-License-Identifier: GPL-3.0-or-later
 
+pub mod contexts;
+pub mod digest;
 pub mod executor;
 pub mod granary;
 pub mod inventory;
+pub mod manifest;
 pub mod modules;
 pub mod planner;
+pub mod reload;
 pub mod state;
 pub mod storage;
 pub mod sync;
+pub mod triage;
+pub mod verify;
 pub mod winnow;
 
-use std::path::Path;
+#[cfg(test)]
+mod tests;
+
+use std::{path::Path, sync::Arc};
 
 use anyhow::Result;
 
-use crate::{conf::config::Config, try_umount};
+use crate::{
+    conf::config::Config,
+    mount::backend::{MountBackend, SystemBackend},
+    try_umount,
+};
 
 pub struct Init;
 
@@ -42,15 +55,28 @@ pub struct Executed {
     pub result: executor::ExecutionResult,
 }
 
+pub struct Verified {
+    pub handle: storage::StorageHandle,
+    pub plan: planner::MountPlan,
+    pub result: executor::ExecutionResult,
+    pub mismatches: Vec<state::MountMismatch>,
+}
+
 pub struct OryzaEngine<S> {
     config: Config,
+    backend: Arc<dyn MountBackend>,
     state: S,
 }
 
 impl OryzaEngine<Init> {
     pub fn new(config: Config) -> Self {
+        Self::with_backend(config, Arc::new(SystemBackend))
+    }
+
+    pub fn with_backend(config: Config, backend: Arc<dyn MountBackend>) -> Self {
         Self {
             config,
+            backend,
             state: Init,
         }
     }
@@ -60,20 +86,13 @@ impl OryzaEngine<Init> {
         mnt_base: &Path,
         img_path: &Path,
     ) -> Result<OryzaEngine<StorageReady>> {
-        let handle = storage::setup(
-            mnt_base,
-            img_path,
-            &self.config.moduledir,
-            self.config.force_ext4,
-            self.config.use_erofs,
-            &self.config.mountsource,
-            self.config.disable_umount,
-        )?;
+        let handle = storage::setup(self.backend.as_ref(), mnt_base, img_path, &self.config)?;
 
         log::info!(">> Storage Backend: [{}]", handle.mode.to_uppercase());
 
         Ok(OryzaEngine {
             config: self.config,
+            backend: self.backend,
             state: StorageReady { handle },
         })
     }
@@ -88,12 +107,19 @@ impl OryzaEngine<StorageReady> {
             modules.len()
         );
 
-        sync::perform_sync(&modules, &self.state.handle.mount_point)?;
+        sync::perform_sync(
+            &modules,
+            &self.state.handle.mount_point,
+            &self.config.sysroot,
+        )?;
 
-        self.state.handle.commit(self.config.disable_umount)?;
+        self.state
+            .handle
+            .commit(self.backend.as_ref(), self.config.disable_umount)?;
 
         Ok(OryzaEngine {
             config: self.config,
+            backend: self.backend,
             state: ModulesReady {
                 handle: self.state.handle,
                 modules,
@@ -114,6 +140,7 @@ impl OryzaEngine<ModulesReady> {
 
         Ok(OryzaEngine {
             config: self.config,
+            backend: self.backend,
             state: Planned {
                 handle: self.state.handle,
                 modules: self.state.modules,
@@ -127,10 +154,11 @@ impl OryzaEngine<Planned> {
     pub fn execute(self) -> Result<OryzaEngine<Executed>> {
         log::info!(">> Link Start! Executing mount plan...");
 
-        let result = executor::execute(&self.state.plan, &self.config)?;
+        let result = executor::execute(&self.state.plan, &self.config, self.backend.as_ref())?;
 
         Ok(OryzaEngine {
             config: self.config,
+            backend: self.backend,
             state: Executed {
                 handle: self.state.handle,
                 modules: self.state.modules,
@@ -142,6 +170,39 @@ impl OryzaEngine<Planned> {
 }
 
 impl OryzaEngine<Executed> {
+    pub fn verify(self) -> OryzaEngine<Verified> {
+        log::info!(">> Verifying mounted module files...");
+
+        let mismatches = verify::verify(&self.state.plan, &self.state.result, &self.config);
+
+        for mismatch in &mismatches {
+            log::warn!(
+                "!! {} does not show {} from {}: {}",
+                mismatch.path.display(),
+                mismatch.source.display(),
+                mismatch.module_id,
+                mismatch.reason
+            );
+        }
+
+        if mismatches.is_empty() {
+            log::info!(">> Verification passed.");
+        }
+
+        OryzaEngine {
+            config: self.config,
+            backend: self.backend,
+            state: Verified {
+                handle: self.state.handle,
+                plan: self.state.plan,
+                result: self.state.result,
+                mismatches,
+            },
+        }
+    }
+}
+
+impl OryzaEngine<Verified> {
     pub fn finalize(self) -> Result<()> {
         let mut nuke_active = false;
 
@@ -169,16 +230,39 @@ impl OryzaEngine<Executed> {
             self.state.result.magic_module_ids.len(),
         );
 
+        let manifest = manifest::ExecutionManifest::build(
+            &self.state.handle.mode,
+            &self.state.handle.mount_point,
+            &self.state.plan,
+            &self.state.result,
+        );
+
+        if let Err(e) = manifest.save() {
+            log::error!("Failed to save execution manifest: {:#}", e);
+        }
+
         let storage_stats = storage::get_usage(&self.state.handle.mount_point);
 
+        let rolled_back = self.state.result.rollback.is_some();
+
         let active_mounts: Vec<String> = self
             .state
             .plan
             .overlay_ops
             .iter()
+            .filter(|_| !rolled_back)
             .map(|op| op.partition_name.clone())
             .collect();
 
+        let errors = self
+            .state
+            .result
+            .fallbacks
+            .iter()
+            .map(|f| f.error.clone())
+            .chain(self.state.result.magic_error.clone())
+            .collect();
+
         let state = state::RuntimeState::new(
             self.state.handle.mode,
             self.state.handle.mount_point,
@@ -187,6 +271,10 @@ impl OryzaEngine<Executed> {
             nuke_active,
             active_mounts,
             storage_stats,
+            errors,
+            self.state.result.culprits,
+            rolled_back,
+            self.state.mismatches,
         );
 
         if let Err(e) = state.save() {
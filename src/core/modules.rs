@@ -5,7 +5,6 @@ use std::{
     collections::HashSet,
     fs::{self, OpenOptions},
     io::{BufRead, BufReader, Write},
-    os::unix::fs::{FileTypeExt, MetadataExt},
     path::{Path, PathBuf},
 };
 
@@ -16,9 +15,10 @@ use crate::{
     conf::config::Config,
     core::{
         inventory::{self, MountMode},
+        manifest::ExecutionManifest,
         state::RuntimeState,
     },
-    defs,
+    defs, utils,
 };
 
 #[derive(Default)]
@@ -62,21 +62,37 @@ struct ModuleInfo {
     description: String,
     mode: String,
     is_mounted: bool,
+    overlay_partitions: Vec<String>,
+    is_magic: bool,
+    fallback_error: Option<String>,
     rules: inventory::ModuleRules,
 }
 
 impl ModuleInfo {
-    fn new(m: inventory::Module, mounted_set: &HashSet<&str>) -> Self {
+    fn new(
+        m: inventory::Module,
+        mounted_set: &HashSet<&str>,
+        manifest: &ExecutionManifest,
+    ) -> Self {
         let prop = ModuleProp::from(m.source_path.join("module.prop").as_path());
 
-        let mode_str = match m.rules.default_mode {
-            MountMode::Overlay => "auto",
+        let mode_str = match m.rules.effective_default() {
+            MountMode::Overlay | MountMode::Auto => "auto",
             MountMode::Magic => "magic",
             MountMode::Ignore => "ignore",
         };
 
+        let overlay_partitions = manifest.overlay_partitions_of(&m.id);
+
+        let is_magic = manifest.magic_modules.contains(&m.id);
+
         Self {
-            is_mounted: mounted_set.contains(m.id.as_str()),
+            is_mounted: mounted_set.contains(m.id.as_str())
+                || is_magic
+                || !overlay_partitions.is_empty(),
+            fallback_error: manifest.fallback_of(&m.id).map(|f| f.error.message.clone()),
+            overlay_partitions,
+            is_magic,
             id: m.id,
             name: prop.name,
             version: prop.version,
@@ -105,9 +121,12 @@ impl ModuleFile {
 
         let file_type = metadata.file_type();
 
-        let is_whiteout = file_type.is_char_device() && metadata.rdev() == 0;
+        let is_whiteout = utils::is_whiteout(&metadata);
 
-        let is_replace = file_type.is_dir() && real_path.join(defs::REPLACE_DIR_FILE_NAME).exists();
+        // Synced storage copies carry the opaque xattr instead of the marker file.
+        let is_replace = file_type.is_dir()
+            && (real_path.join(defs::REPLACE_DIR_FILE_NAME).exists()
+                || utils::is_overlay_opaque(&real_path));
 
         let is_replace_file = real_path
             .file_name()
@@ -131,6 +150,8 @@ pub fn print_list(config: &Config) -> Result<()> {
 
     let state = RuntimeState::load().unwrap_or_default();
 
+    let manifest = ExecutionManifest::load().unwrap_or_default();
+
     let mounted_ids: HashSet<&str> = state
         .overlay_modules
         .iter()
@@ -140,7 +161,7 @@ pub fn print_list(config: &Config) -> Result<()> {
 
     let infos: Vec<ModuleInfo> = modules
         .into_iter()
-        .map(|m| ModuleInfo::new(m, &mounted_ids))
+        .map(|m| ModuleInfo::new(m, &mounted_ids, &manifest))
         .collect();
 
     println!("{}", serde_json::to_string(&infos)?);
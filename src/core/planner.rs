@@ -2,35 +2,87 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     fs,
+    os::unix::fs::{FileTypeExt, MetadataExt},
     path::{Path, PathBuf},
 };
 
 use anyhow::Result;
 use rayon::prelude::*;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use walkdir::WalkDir;
 
 use crate::{
     conf::config,
-    core::inventory::{Module, MountMode},
+    core::{
+        digest::HashCache,
+        inventory::{Module, ModuleRules, MountMode},
+        triage::ModeDecision,
+        winnow,
+    },
     defs,
+    mount::{
+        magic::{self, MagicRequest},
+        node::{Node, NodeFileType},
+    },
+    utils,
 };
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct OverlayOperation {
     pub partition_name: String,
     pub target: String,
     pub lowerdirs: Vec<PathBuf>,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ForcedSelection {
+    pub partition_name: String,
+    pub module_id: String,
+    pub target: PathBuf,
+    pub source: PathBuf,
+}
+
+#[derive(Debug, Default, Serialize)]
 pub struct MountPlan {
     pub overlay_ops: Vec<OverlayOperation>,
     pub magic_module_paths: Vec<PathBuf>,
     pub overlay_module_ids: Vec<String>,
     pub magic_module_ids: Vec<String>,
+    pub forced_selections: Vec<ForcedSelection>,
+    pub magic_rules: HashMap<PathBuf, ModuleRules>,
+    pub module_order: Vec<String>,
+    // Module id -> the mount mode it was given and why.
+    pub mode_decisions: BTreeMap<String, ModeDecision>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictScope {
+    #[default]
+    Overlay,
+    Magic,
+    Mixed,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConflictKind {
+    #[default]
+    Content,
+    Deletion,
+    OpaqueShadow,
+    TypeMismatch,
+    Symlink,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ContentVerdict {
+    Identical,
+    #[default]
+    Divergent,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -38,6 +90,9 @@ pub struct ConflictEntry {
     pub partition: String,
     pub relative_path: String,
     pub contending_modules: Vec<String>,
+    pub scope: ConflictScope,
+    pub kind: ConflictKind,
+    pub verdict: ContentVerdict,
 }
 
 #[derive(Debug, Default)]
@@ -45,47 +100,244 @@ pub struct ConflictReport {
     pub details: Vec<ConflictEntry>,
 }
 
+// (partition, relative path) -> (module id, path inside the module), in precedence order.
+type ContenderIndex = HashMap<(String, String), Vec<(String, PathBuf)>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EntryShape {
+    File,
+    Directory { opaque: bool },
+    Symlink,
+    Whiteout,
+}
+
+impl EntryShape {
+    fn of(path: &Path) -> Option<Self> {
+        let metadata = fs::symlink_metadata(path).ok()?;
+
+        let file_type = metadata.file_type();
+
+        let shape = if file_type.is_char_device() && metadata.rdev() == 0 {
+            Self::Whiteout
+        } else if file_type.is_dir() {
+            let opaque =
+                path.join(defs::REPLACE_DIR_FILE_NAME).exists() || utils::is_overlay_opaque(path);
+
+            Self::Directory { opaque }
+        } else if file_type.is_symlink() {
+            Self::Symlink
+        } else {
+            Self::File
+        };
+
+        Some(shape)
+    }
+}
+
+// Paths are in precedence order, `None` when they don't conflict.
+pub fn conflict_kind<P: AsRef<Path>>(paths: &[P]) -> Option<ConflictKind> {
+    let shapes: Vec<EntryShape> = paths
+        .iter()
+        .map(|path| EntryShape::of(path.as_ref()))
+        .collect::<Option<_>>()?;
+
+    classify(&shapes)
+}
+
+// Shapes are in precedence order. Directories that merge cleanly and modules
+// that agree on deleting a path are not conflicts.
+fn classify(shapes: &[EntryShape]) -> Option<ConflictKind> {
+    let whiteouts = shapes
+        .iter()
+        .filter(|s| **s == EntryShape::Whiteout)
+        .count();
+
+    if whiteouts == shapes.len() {
+        return None;
+    }
+
+    if whiteouts > 0 {
+        return Some(ConflictKind::Deletion);
+    }
+
+    let dirs = shapes
+        .iter()
+        .filter(|s| matches!(s, EntryShape::Directory { .. }))
+        .count();
+
+    if dirs == shapes.len() {
+        // An opaque directory only hides the layers below it.
+        let (_, above_lowest) = shapes.split_last()?;
+
+        return above_lowest
+            .contains(&EntryShape::Directory { opaque: true })
+            .then_some(ConflictKind::OpaqueShadow);
+    }
+
+    if dirs > 0 {
+        return Some(ConflictKind::TypeMismatch);
+    }
+
+    if shapes.contains(&EntryShape::Symlink) {
+        return Some(ConflictKind::Symlink);
+    }
+
+    Some(ConflictKind::Content)
+}
+
+// Unreadable contenders count as divergent so they are never hidden.
+fn compare_contents(kind: ConflictKind, paths: &[&PathBuf], cache: &HashCache) -> ContentVerdict {
+    let identical = match kind {
+        ConflictKind::Content => {
+            let sizes: Option<HashSet<u64>> = paths
+                .iter()
+                .map(|path| fs::metadata(path).ok().map(|m| m.len()))
+                .collect();
+
+            sizes.is_some_and(|sizes| sizes.len() == 1)
+                && paths
+                    .iter()
+                    .map(|path| cache.hash(path).ok())
+                    .collect::<Option<HashSet<String>>>()
+                    .is_some_and(|hashes| hashes.len() == 1)
+        }
+        ConflictKind::Symlink => paths
+            .iter()
+            .map(|path| fs::read_link(path).ok())
+            .collect::<Option<HashSet<PathBuf>>>()
+            .is_some_and(|targets| targets.len() == 1),
+        _ => false,
+    };
+
+    if identical {
+        ContentVerdict::Identical
+    } else {
+        ContentVerdict::Divergent
+    }
+}
+
+impl OverlayOperation {
+    pub fn layer_module_id(layer_path: &Path) -> String {
+        layer_path
+            .parent()
+            .and_then(|p| p.file_name())
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_else(|| "UNKNOWN".into())
+    }
+
+    pub fn index_layer_entries(&self) -> HashMap<String, Vec<usize>> {
+        let mut entry_map: HashMap<String, Vec<usize>> = HashMap::new();
+
+        for (idx, layer_path) in self.lowerdirs.iter().enumerate() {
+            for entry in WalkDir::new(layer_path).min_depth(1).into_iter().flatten() {
+                if entry.file_name() == defs::REPLACE_DIR_FILE_NAME {
+                    continue;
+                }
+
+                if let Ok(rel) = entry.path().strip_prefix(layer_path) {
+                    let rel_str = rel.to_string_lossy().to_string();
+
+                    entry_map.entry(rel_str).or_default().push(idx);
+                }
+            }
+        }
+
+        entry_map
+    }
+}
+
 impl MountPlan {
-    pub fn analyze_conflicts(&self) -> ConflictReport {
-        let mut conflicts: Vec<ConflictEntry> = self
+    pub fn precedence_of(&self, module_id: &str) -> usize {
+        self.module_order
+            .iter()
+            .position(|id| id == module_id)
+            .unwrap_or(self.module_order.len())
+    }
+
+    pub fn analyze_conflicts(&self, config: &config::Config, cache: &HashCache) -> ConflictReport {
+        let overlay_entries: ContenderIndex = self
             .overlay_ops
             .par_iter()
             .flat_map(|op| {
-                let mut local_conflicts = Vec::new();
-
-                let mut file_map: HashMap<String, Vec<String>> = HashMap::new();
-
-                for layer_path in &op.lowerdirs {
-                    let module_id = layer_path
-                        .parent()
-                        .and_then(|p| p.file_name())
-                        .map(|s| s.to_string_lossy().to_string())
-                        .unwrap_or_else(|| "UNKNOWN".into());
+                op.index_layer_entries()
+                    .into_iter()
+                    .map(|(rel_path, layers)| {
+                        let contenders = layers
+                            .iter()
+                            .map(|&i| {
+                                let layer = &op.lowerdirs[i];
+
+                                (
+                                    OverlayOperation::layer_module_id(layer),
+                                    layer.join(&rel_path),
+                                )
+                            })
+                            .collect();
+
+                        ((op.partition_name.clone(), rel_path), contenders)
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect();
 
-                    for entry in WalkDir::new(layer_path).min_depth(1).into_iter().flatten() {
-                        if !entry.file_type().is_file() {
-                            continue;
-                        }
+        let mut magic_entries = HashMap::new();
 
-                        if let Ok(rel) = entry.path().strip_prefix(layer_path) {
-                            let rel_str = rel.to_string_lossy().to_string();
+        match self.magic_tree(config) {
+            Ok(Some(root)) => self.index_magic_entries(&root, Path::new(""), &mut magic_entries),
+            Ok(None) => {}
+            Err(e) => log::warn!("Skipping magic conflict analysis: {:#}", e),
+        }
 
-                            file_map.entry(rel_str).or_default().push(module_id.clone());
-                        }
+        let keys: HashSet<&(String, String)> =
+            overlay_entries.keys().chain(magic_entries.keys()).collect();
+
+        let mut conflicts: Vec<ConflictEntry> = keys
+            .into_par_iter()
+            .filter_map(|key| {
+                let overlay = overlay_entries
+                    .get(key)
+                    .map(Vec::as_slice)
+                    .unwrap_or_default();
+
+                let magic = magic_entries
+                    .get(key)
+                    .map(Vec::as_slice)
+                    .unwrap_or_default();
+
+                // Magic mount binds on top of whatever OverlayFS serves, so its
+                // contenders take precedence.
+                let mut contenders: Vec<&(String, PathBuf)> = Vec::new();
+
+                for contender in magic.iter().chain(overlay) {
+                    if !contenders.iter().any(|(id, _)| *id == contender.0) {
+                        contenders.push(contender);
                     }
                 }
 
-                for (rel_path, modules) in file_map {
-                    if modules.len() > 1 {
-                        local_conflicts.push(ConflictEntry {
-                            partition: op.partition_name.clone(),
-                            relative_path: rel_path,
-                            contending_modules: modules,
-                        });
-                    }
+                if contenders.len() < 2 {
+                    return None;
                 }
 
-                local_conflicts
+                let paths: Vec<&PathBuf> = contenders.iter().map(|(_, path)| path).collect();
+
+                let kind = conflict_kind(&paths)?;
+
+                let verdict = compare_contents(kind, &paths, cache);
+
+                let scope = match (overlay.is_empty(), magic.is_empty()) {
+                    (false, true) => ConflictScope::Overlay,
+                    (true, false) => ConflictScope::Magic,
+                    _ => ConflictScope::Mixed,
+                };
+
+                Some(ConflictEntry {
+                    partition: key.0.clone(),
+                    relative_path: key.1.clone(),
+                    contending_modules: contenders.iter().map(|(id, _)| id.clone()).collect(),
+                    scope,
+                    kind,
+                    verdict,
+                })
             })
             .collect();
 
@@ -98,6 +350,66 @@ impl MountPlan {
         ConflictReport { details: conflicts }
     }
 
+    fn magic_tree(&self, config: &config::Config) -> Result<Option<Node>> {
+        if self.magic_module_paths.is_empty() {
+            return Ok(None);
+        }
+
+        magic::build_tree(&MagicRequest {
+            tmp_path: Path::new(""),
+            module_paths: &self.magic_module_paths,
+            sysroot: &config.sysroot,
+            mount_source: &config.mountsource,
+            extra_partitions: &config.partitions,
+            exclusions: HashMap::new(),
+            module_rules: &self.magic_rules,
+            disable_umount: config.disable_umount,
+        })
+    }
+
+    fn magic_module_id(&self, file: &Path) -> String {
+        self.magic_module_paths
+            .iter()
+            .find(|root| file.starts_with(root))
+            .and_then(|root| root.file_name())
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_else(|| "UNKNOWN".into())
+    }
+
+    fn index_magic_entries(&self, node: &Node, path: &Path, entries: &mut ContenderIndex) {
+        for child in node.children.values() {
+            let child_path = path.join(&child.name);
+
+            if child.file_type == NodeFileType::Directory {
+                self.index_magic_entries(child, &child_path, entries);
+            }
+
+            let mut components = child_path.components();
+
+            let Some(partition) = components.next() else {
+                continue;
+            };
+
+            if child.contributors.is_empty() {
+                continue;
+            }
+
+            let contenders = child
+                .contributors
+                .iter()
+                .map(|file| (self.magic_module_id(file), file.clone()))
+                .collect();
+
+            entries.insert(
+                (
+                    partition.as_os_str().to_string_lossy().to_string(),
+                    components.as_path().to_string_lossy().to_string(),
+                ),
+                contenders,
+            );
+        }
+    }
+
     pub fn print_visuals(&self) {
         if self.overlay_ops.is_empty() && self.magic_module_paths.is_empty() {
             log::info!(">> Empty plan. Standby mode.");
@@ -138,6 +450,23 @@ impl MountPlan {
             }
         }
 
+        if !self.forced_selections.is_empty() {
+            log::info!("[Winnowing Overrides]");
+
+            for (i, sel) in self.forced_selections.iter().enumerate() {
+                let is_last = i == self.forced_selections.len() - 1;
+
+                let branch = if is_last { "╰──" } else { "├──" };
+
+                log::info!(
+                    "{} [Force] {} <- {}",
+                    branch,
+                    sel.target.display(),
+                    sel.module_id
+                );
+            }
+        }
+
         if !self.magic_module_paths.is_empty() {
             log::info!("[Magic Mount Fallback Protocol]");
 
@@ -160,7 +489,7 @@ impl MountPlan {
 struct ModuleContribution {
     id: String,
     overlays: Vec<(String, PathBuf)>,
-    magic_path: Option<PathBuf>,
+    magic: Option<(PathBuf, ModuleRules)>,
 }
 
 pub fn generate(
@@ -174,28 +503,43 @@ pub fn generate(
 
     target_partitions.extend(config.partitions.iter().map(|s| s.as_str()));
 
+    // The module dir still holds magic-only files and unconverted markers, so
+    // it cannot stand in for a missing synced copy as an OverlayFS layer.
+    let unsynced = |module: &Module| {
+        module.rules.wants_mode(&MountMode::Overlay) && !storage_root.join(&module.id).exists()
+    };
+
     let contributions: Vec<Option<ModuleContribution>> = modules
         .par_iter()
         .map(|module| {
-            let mut content_path = storage_root.join(&module.id);
-
-            if !content_path.exists() {
-                content_path = module.source_path.clone();
-            }
-
-            if !content_path.exists() {
+            if !module.source_path.exists() {
                 return None;
             }
 
+            let layer_root = storage_root.join(&module.id);
+
+            let synced = !unsynced(module);
+
+            let rules = if synced {
+                module.rules.clone()
+            } else {
+                log::warn!(
+                    "No synced copy of {}, sending its OverlayFS partitions to Magic Mount",
+                    module.id
+                );
+
+                module.rules.overlay_as_magic()
+            };
+
             let mut contrib = ModuleContribution {
                 id: module.id.clone(),
                 overlays: Vec::new(),
-                magic_path: None,
+                magic: None,
             };
 
             let mut has_any_action = false;
 
-            if let Ok(entries) = fs::read_dir(&content_path) {
+            if let Ok(entries) = fs::read_dir(&module.source_path) {
                 for entry in entries.flatten() {
                     let path = entry.path();
 
@@ -213,22 +557,44 @@ pub fn generate(
                         continue;
                     }
 
-                    let mode = module.rules.get_mode(&dir_name);
+                    let wants_overlay =
+                        synced && module.rules.selects(&dir_name, true, &MountMode::Overlay);
 
-                    match mode {
-                        MountMode::Overlay => {
-                            contrib.overlays.push((dir_name, path));
+                    let wants_magic = rules.selects(&dir_name, true, &MountMode::Magic);
 
-                            has_any_action = true;
-                        }
-                        MountMode::Magic => {
-                            contrib.magic_path = Some(content_path.clone());
+                    if wants_overlay {
+                        let layer = layer_root.join(&dir_name);
+
+                        if has_files(&layer) {
+                            contrib.overlays.push((dir_name.clone(), layer));
 
                             has_any_action = true;
                         }
-                        MountMode::Ignore => {
-                            log::debug!("Ignoring {}/{} per rule", module.id, dir_name);
-                        }
+                    }
+
+                    if wants_magic {
+                        contrib.magic = Some((module.source_path.clone(), rules.clone()));
+
+                        has_any_action = true;
+                    }
+
+                    if !wants_overlay && !wants_magic {
+                        log::debug!(
+                            "Ignoring {}/{} per rule '{}'",
+                            module.id,
+                            dir_name,
+                            module
+                                .rules
+                                .get_mode(&dir_name)
+                                .pattern
+                                .unwrap_or("default")
+                        );
+                    } else if wants_overlay && wants_magic {
+                        log::debug!(
+                            "Splitting {}/{} between OverlayFS and Magic Mount",
+                            module.id,
+                            dir_name
+                        );
                     }
                 }
             }
@@ -239,15 +605,19 @@ pub fn generate(
 
     let mut overlay_groups: HashMap<String, Vec<PathBuf>> = HashMap::new();
 
-    let mut magic_paths = HashSet::new();
+    let mut magic_paths = Vec::new();
+
+    let mut magic_rules = HashMap::new();
 
     let mut overlay_ids = HashSet::new();
 
     let mut magic_ids = HashSet::new();
 
     for contrib in contributions.into_iter().flatten() {
-        if let Some(path) = contrib.magic_path {
-            magic_paths.insert(path);
+        if let Some((path, rules)) = contrib.magic {
+            magic_paths.push(path.clone());
+
+            magic_rules.insert(path, rules);
 
             magic_ids.insert(contrib.id.clone());
         }
@@ -260,17 +630,15 @@ pub fn generate(
     }
 
     for (part, layers) in overlay_groups {
-        let initial_target_path = format!("/{}", part);
-
-        let target_path_obj = Path::new(&initial_target_path);
+        let target_path_obj = config.sysroot.join(&part);
 
-        if fs::symlink_metadata(target_path_obj)
+        if fs::symlink_metadata(&target_path_obj)
             .map(|m| m.file_type().is_symlink())
             .unwrap_or(false)
         {
             log::warn!(
                 "Skipping overlay on symlink partition: {}",
-                initial_target_path
+                target_path_obj.display()
             );
 
             continue;
@@ -296,7 +664,30 @@ pub fn generate(
         });
     }
 
-    plan.magic_module_paths = magic_paths.into_iter().collect();
+    winnow::enforce_selections(&mut plan, &config.winnowing);
+
+    plan.magic_module_paths = magic_paths;
+
+    plan.module_order = modules.iter().map(|m| m.id.clone()).collect();
+
+    plan.mode_decisions = modules
+        .iter()
+        .map(|m| {
+            let decision = if unsynced(m) {
+                ModeDecision {
+                    mode: MountMode::Magic,
+                    reason: "no synced copy in storage".to_string(),
+                    ..Default::default()
+                }
+            } else {
+                m.decision.clone()
+            };
+
+            (m.id.clone(), decision)
+        })
+        .collect();
+
+    plan.magic_rules = magic_rules;
 
     plan.overlay_module_ids = overlay_ids.into_iter().collect();
 
@@ -318,3 +709,233 @@ fn has_files(path: &Path) -> bool {
 
     false
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use anyhow::ensure;
+
+    use super::{ConflictKind, ConflictScope, ContentVerdict, generate};
+    use crate::{
+        conf::config::Config,
+        core::{
+            digest::HashCache,
+            inventory::{self, MountMode},
+            tests::{sandboxed, simulated},
+        },
+    };
+
+    #[test]
+    fn conflict_report_covers_magic_and_mixed_collisions() {
+        simulated("conflict_report_covers_magic_and_mixed_collisions", |fx| {
+            fx.system_file("system/etc/hosts", "stock")?;
+
+            fx.system_file("system/bin/tool", "stock")?;
+
+            fx.module_file("alpha", "system/etc/hosts", "alpha")?;
+
+            fx.module_file("beta", "system/etc/hosts", "beta")?;
+
+            fx.module_file("delta", "system/bin/tool", "delta")?;
+
+            fx.module_file("gamma", "system/bin/tool", "gamma")?;
+
+            for id in ["alpha", "beta", "delta"] {
+                fx.module_rules(id, r#"{"default_mode": "magic"}"#)?;
+            }
+
+            let config = fx.config();
+
+            let modules = inventory::scan(&fx.moduledir, &config)?;
+
+            let plan = generate(&config, &modules, &fx.moduledir)?;
+
+            let report = plan.analyze_conflicts(&config, &HashCache::default());
+
+            let summary: Vec<(String, Vec<String>, ConflictScope)> = report
+                .details
+                .into_iter()
+                .map(|c| {
+                    (
+                        format!("{}/{}", c.partition, c.relative_path),
+                        c.contending_modules,
+                        c.scope,
+                    )
+                })
+                .collect();
+
+            ensure!(
+                summary
+                    == [
+                        (
+                            "system/bin/tool".to_string(),
+                            vec!["delta".to_string(), "gamma".to_string()],
+                            ConflictScope::Mixed,
+                        ),
+                        (
+                            "system/etc/hosts".to_string(),
+                            vec!["beta".to_string(), "alpha".to_string()],
+                            ConflictScope::Magic,
+                        ),
+                    ],
+                "unexpected conflicts: {:?}",
+                summary
+            );
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn conflict_report_classifies_entry_kinds() {
+        sandboxed(
+            module_path!(),
+            "conflict_report_classifies_entry_kinds",
+            |fx| {
+                fx.module_file("alpha", "system/etc/perms", "alpha")?;
+
+                fx.module_file("beta", "system/etc/perms/extra.xml", "beta")?;
+
+                fx.module_file("alpha", "system/app/Foo/.replace", "")?;
+
+                fx.module_file("alpha", "system/app/Foo/base.apk", "alpha")?;
+
+                fx.module_file("beta", "system/app/Foo/lib.so", "beta")?;
+
+                fx.module_file("alpha", "system/app/Bar/base.apk", "alpha")?;
+
+                fx.module_file("beta", "system/app/Bar/lib.so", "beta")?;
+
+                fx.module_file("beta", "system/bin/sh", "beta")?;
+
+                fs::create_dir_all(fx.moduledir.join("alpha/system/bin"))?;
+
+                std::os::unix::fs::symlink("toybox", fx.moduledir.join("alpha/system/bin/sh"))?;
+
+                fx.module_file("beta", "system/etc/hosts", "beta")?;
+
+                rustix::fs::mknodat(
+                    rustix::fs::CWD,
+                    fx.moduledir.join("alpha/system/etc/hosts"),
+                    rustix::fs::FileType::CharacterDevice,
+                    rustix::fs::Mode::from_raw_mode(0o644),
+                    0,
+                )?;
+
+                let config = Config {
+                    priority: vec!["alpha".to_string(), "beta".to_string()],
+                    ..fx.config()
+                };
+
+                let modules = inventory::scan(&fx.moduledir, &config)?;
+
+                let plan = generate(&config, &modules, &fx.moduledir)?;
+
+                let kinds: Vec<(String, ConflictKind)> = plan
+                    .analyze_conflicts(&config, &HashCache::default())
+                    .details
+                    .into_iter()
+                    .map(|c| (c.relative_path, c.kind))
+                    .collect();
+
+                ensure!(
+                    kinds
+                        == [
+                            ("app/Foo".to_string(), ConflictKind::OpaqueShadow),
+                            ("bin/sh".to_string(), ConflictKind::Symlink),
+                            ("etc/hosts".to_string(), ConflictKind::Deletion),
+                            ("etc/perms".to_string(), ConflictKind::TypeMismatch),
+                        ],
+                    "unexpected conflicts: {:?}",
+                    kinds
+                );
+
+                Ok(())
+            },
+        );
+    }
+
+    #[test]
+    fn conflict_report_separates_identical_copies() {
+        simulated("conflict_report_separates_identical_copies", |fx| {
+            fx.module_file("alpha", "system/lib/libfoo.so", "same bytes")?;
+
+            fx.module_file("beta", "system/lib/libfoo.so", "same bytes")?;
+
+            fx.module_file("alpha", "system/etc/hosts", "alpha")?;
+
+            fx.module_file("beta", "system/etc/hosts", "beta!")?;
+
+            let config = fx.config();
+
+            let modules = inventory::scan(&fx.moduledir, &config)?;
+
+            let plan = generate(&config, &modules, &fx.moduledir)?;
+
+            let cache_file = fx.moduledir.with_file_name("hash_cache.json");
+
+            for _ in 0..2 {
+                let cache = HashCache::load(&cache_file);
+
+                let verdicts: Vec<(String, ContentVerdict)> = plan
+                    .analyze_conflicts(&config, &cache)
+                    .details
+                    .into_iter()
+                    .map(|c| (c.relative_path, c.verdict))
+                    .collect();
+
+                ensure!(
+                    verdicts
+                        == [
+                            ("etc/hosts".to_string(), ContentVerdict::Divergent),
+                            ("lib/libfoo.so".to_string(), ContentVerdict::Identical),
+                        ],
+                    "unexpected verdicts: {:?}",
+                    verdicts
+                );
+
+                cache.save()?;
+            }
+
+            ensure!(cache_file.exists());
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn unsynced_module_falls_back_to_magic() {
+        simulated("unsynced_module_falls_back_to_magic", |fx| {
+            fx.module_file("alpha", "system/etc/hosts", "alpha")?;
+
+            fx.module_file("beta", "system/etc/beta.conf", "beta")?;
+
+            fx.module_rules("beta", r#"{"default_mode": "magic"}"#)?;
+
+            fs::create_dir_all(&fx.storage)?;
+
+            let config = fx.config();
+
+            let modules = inventory::scan(&fx.moduledir, &config)?;
+
+            let plan = generate(&config, &modules, &fx.storage)?;
+
+            ensure!(plan.overlay_ops.is_empty(), "{:?}", plan.overlay_ops);
+
+            ensure!(plan.overlay_module_ids.is_empty());
+
+            ensure!(plan.magic_module_ids == ["alpha", "beta"]);
+
+            ensure!(plan.mode_decisions["alpha"].mode == MountMode::Magic);
+
+            ensure!(plan.magic_rules[&fx.moduledir.join("alpha")].selects(
+                "system/etc/hosts",
+                false,
+                &MountMode::Magic
+            ));
+
+            Ok(())
+        });
+    }
+}
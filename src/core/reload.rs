@@ -0,0 +1,328 @@
+// Copyright 2025 Meta-Hybrid Mount Authors
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{
+    collections::{BTreeSet, HashSet},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result, bail};
+use serde::Serialize;
+
+use crate::{
+    conf::config::Config,
+    core::{
+        executor, inventory,
+        manifest::{ExecutionManifest, OverlayEntry, OverlayStatus},
+        planner::{self, ForcedSelection, OverlayOperation},
+        state::RuntimeState,
+        sync,
+    },
+    error::{self, ErrorCode},
+    mount::{
+        backend::SystemBackend,
+        journal::{self, MountKind, MountRecord},
+        overlay::{self, SwapOutcome},
+    },
+    utils,
+};
+
+#[derive(Debug, Serialize)]
+pub struct ReloadFailure {
+    pub partition: String,
+    pub code: ErrorCode,
+    pub error: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ReloadReport {
+    pub synced: Vec<String>,
+    pub swapped: Vec<String>,
+    pub mounted: Vec<String>,
+    pub released: Vec<String>,
+    pub unchanged: Vec<String>,
+    pub needs_reboot: Vec<String>,
+    pub failed: Vec<ReloadFailure>,
+}
+
+fn live_root<'a>(mounts: &'a [MountRecord], target: &str) -> Option<&'a MountRecord> {
+    mounts
+        .iter()
+        .rev()
+        .find(|r| r.kind == MountKind::OverlayRoot && r.target == Path::new(target))
+        .filter(|r| journal::is_live(r))
+}
+
+// Magic mounts and pins sit on top of the overlay root and would be lost with it.
+fn stacked_blocker(mounts: &[MountRecord], target: &str) -> Option<String> {
+    mounts
+        .iter()
+        .filter(|r| {
+            matches!(
+                r.kind,
+                MountKind::MagicFile | MountKind::MagicSkeleton | MountKind::WinnowPin
+            )
+        })
+        .find(|r| r.target.starts_with(target) && journal::is_live(r))
+        .map(|r| r.target.display().to_string())
+}
+
+fn layer_strings(op: &OverlayOperation) -> Vec<String> {
+    op.lowerdirs
+        .iter()
+        .map(|p| p.display().to_string())
+        .collect()
+}
+
+pub fn reload(config: &Config) -> Result<ReloadReport> {
+    let mut state = RuntimeState::load().context("Failed to load runtime state")?;
+
+    let mut manifest = ExecutionManifest::load().context("Failed to load execution manifest")?;
+
+    if manifest.mounts.is_empty() {
+        bail!("No active mount set recorded, nothing to reload");
+    }
+
+    if state.storage_mode.starts_with("erofs") {
+        bail!("EROFS storage is read-only, a reboot is required to apply module changes");
+    }
+
+    let storage_root = state.mount_point.clone();
+
+    if !utils::is_mounted(&storage_root) {
+        bail!("Storage {} is not mounted", storage_root.display());
+    }
+
+    let modules = inventory::scan(&config.moduledir, config).context("Inventory scan failed")?;
+
+    let changed: HashSet<String> = sync::perform_sync(&modules, &storage_root, &config.sysroot)
+        .context("Module sync failed")?
+        .into_iter()
+        .collect();
+
+    let plan =
+        planner::generate(config, &modules, &storage_root).context("Plan generation failed")?;
+
+    let mut report = ReloadReport {
+        synced: changed.iter().cloned().collect(),
+        ..Default::default()
+    };
+
+    report.synced.sort();
+
+    let mut remounted = HashSet::new();
+
+    for op in &plan.overlay_ops {
+        let lowerdirs = layer_strings(op);
+
+        let touched = op
+            .lowerdirs
+            .iter()
+            .any(|l| changed.contains(&OverlayOperation::layer_module_id(l)));
+
+        let is_live = live_root(&manifest.mounts, &op.target).is_some();
+
+        let previous = manifest
+            .overlays
+            .iter()
+            .find(|o| o.partition == op.partition_name);
+
+        if is_live
+            && !touched
+            && previous.is_some_and(|o| {
+                o.status == OverlayStatus::Mounted
+                    && o.target == op.target
+                    && o.lowerdirs == lowerdirs
+            })
+        {
+            report.unchanged.push(op.partition_name.clone());
+
+            continue;
+        }
+
+        if let Some(blocker) = stacked_blocker(&manifest.mounts, &op.target) {
+            report.needs_reboot.push(format!(
+                "{}: mounts stacked on top ({})",
+                op.partition_name, blocker
+            ));
+
+            continue;
+        }
+
+        let (upper, work) = executor::rw_layers(&op.partition_name);
+
+        log::info!(
+            "Reloading {} [OVERLAY] (Layers: {})",
+            op.target,
+            lowerdirs.len()
+        );
+
+        let result = if is_live {
+            overlay::swap_overlay(&op.target, &lowerdirs, work, upper, config.disable_umount)
+                .map(Some)
+        } else {
+            overlay::mount_overlay(&op.target, &lowerdirs, work, upper, config.disable_umount)
+                .map(|_| None)
+        };
+
+        match result {
+            Ok(Some(outcome)) => {
+                if outcome == SwapOutcome::Replaced {
+                    manifest
+                        .mounts
+                        .retain(|r| !r.target.starts_with(&op.target));
+                }
+
+                report.swapped.push(op.partition_name.clone());
+            }
+            Ok(None) => report.mounted.push(op.partition_name.clone()),
+            Err(e) => {
+                report.failed.push(ReloadFailure {
+                    partition: op.partition_name.clone(),
+                    code: error::code_of(&e),
+                    error: format!("{:#}", e),
+                });
+
+                continue;
+            }
+        }
+
+        let child_mounts = overlay::get_sub_mounts(&op.target)
+            .unwrap_or_default()
+            .into_iter()
+            .map(PathBuf::from)
+            .collect();
+
+        manifest
+            .overlays
+            .retain(|o| o.partition != op.partition_name);
+
+        manifest.overlays.push(OverlayEntry {
+            partition: op.partition_name.clone(),
+            target: op.target.clone(),
+            lowerdirs,
+            child_mounts,
+            status: OverlayStatus::Mounted,
+        });
+
+        manifest
+            .fallbacks
+            .retain(|f| f.partition != op.partition_name);
+
+        remounted.insert(op.partition_name.clone());
+    }
+
+    let planned: HashSet<&str> = plan
+        .overlay_ops
+        .iter()
+        .map(|op| op.partition_name.as_str())
+        .collect();
+
+    let stale: Vec<OverlayEntry> = manifest
+        .overlays
+        .iter()
+        .filter(|o| !planned.contains(o.partition.as_str()))
+        .cloned()
+        .collect();
+
+    for entry in stale {
+        if let Some(blocker) = stacked_blocker(&manifest.mounts, &entry.target) {
+            report.needs_reboot.push(format!(
+                "{}: mounts stacked on top ({})",
+                entry.partition, blocker
+            ));
+
+            continue;
+        }
+
+        let (owned, rest): (Vec<MountRecord>, Vec<MountRecord>) = manifest
+            .mounts
+            .drain(..)
+            .partition(|r| r.target.starts_with(&entry.target));
+
+        let teardown = journal::unwind(&owned);
+
+        manifest.mounts = rest;
+
+        if teardown.failed.is_empty() {
+            manifest.overlays.retain(|o| o.partition != entry.partition);
+
+            manifest
+                .fallbacks
+                .retain(|f| f.partition != entry.partition);
+
+            report.released.push(entry.partition);
+        } else {
+            let failed: Vec<&Path> = teardown.failed.iter().map(|f| f.target.as_path()).collect();
+
+            manifest.mounts.extend(
+                owned
+                    .into_iter()
+                    .filter(|r| failed.contains(&r.target.as_path())),
+            );
+
+            report.failed.push(ReloadFailure {
+                partition: entry.partition,
+                code: ErrorCode::Unmount,
+                error: format!("{} mounts could not be detached", teardown.failed.len()),
+            });
+        }
+    }
+
+    let pins: Vec<ForcedSelection> = plan
+        .forced_selections
+        .iter()
+        .filter(|sel| remounted.contains(&sel.partition_name))
+        .cloned()
+        .collect();
+
+    executor::apply_forced_selections(&pins, &remounted, config.disable_umount, &SystemBackend);
+
+    let running_magic: BTreeSet<&String> = manifest.magic_modules.iter().collect();
+
+    let planned_magic: BTreeSet<&String> = plan.magic_module_ids.iter().collect();
+
+    if running_magic != planned_magic {
+        report
+            .needs_reboot
+            .push("magic mount module set changed".to_string());
+    }
+
+    let fresh = journal::snapshot();
+
+    manifest
+        .pins
+        .retain(|sel| !remounted.contains(&sel.partition_name));
+
+    manifest.pins.extend(pins.into_iter().filter(|sel| {
+        fresh
+            .iter()
+            .any(|r| r.kind == MountKind::WinnowPin && r.target == sel.target)
+    }));
+
+    manifest.mounts.extend(fresh);
+
+    manifest
+        .save()
+        .context("Failed to update execution manifest")?;
+
+    let live: Vec<&OverlayEntry> = manifest
+        .overlays
+        .iter()
+        .filter(|o| o.status == OverlayStatus::Mounted)
+        .collect();
+
+    state.active_mounts = live.iter().map(|o| o.partition.clone()).collect();
+
+    let mut overlay_ids: Vec<String> = live.iter().flat_map(|o| o.module_ids()).collect();
+
+    overlay_ids.sort();
+
+    overlay_ids.dedup();
+
+    state.overlay_modules = overlay_ids;
+
+    state.save().context("Failed to update runtime state")?;
+
+    Ok(report)
+}
@@ -10,7 +10,29 @@ use std::{
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
 
-use crate::defs;
+use crate::{
+    core::executor::{DiagnosticIssue, DiagnosticLevel},
+    defs,
+    error::{ErrorCode, ErrorReport},
+};
+
+// A module layer that broke the overlay of a partition and went to magic mount
+// on its own.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OverlayCulprit {
+    pub module_id: String,
+    pub partition: String,
+    pub error: ErrorReport,
+}
+
+// A module file that the verification pass did not find at its live path.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MountMismatch {
+    pub module_id: String,
+    pub path: PathBuf,
+    pub source: PathBuf,
+    pub reason: String,
+}
 
 #[derive(Debug, Serialize, Deserialize, Default)]
 pub struct RuntimeState {
@@ -31,6 +53,14 @@ pub struct RuntimeState {
     pub storage_percent: u8,
     #[serde(default)]
     pub zygisksu_enforce: bool,
+    #[serde(default)]
+    pub errors: Vec<ErrorReport>,
+    #[serde(default)]
+    pub overlay_culprits: Vec<OverlayCulprit>,
+    #[serde(default)]
+    pub rolled_back: bool,
+    #[serde(default)]
+    pub mount_mismatches: Vec<MountMismatch>,
 }
 
 impl RuntimeState {
@@ -43,6 +73,10 @@ impl RuntimeState {
         nuke_active: bool,
         active_mounts: Vec<String>,
         storage_info: (u64, u64, u8),
+        errors: Vec<ErrorReport>,
+        overlay_culprits: Vec<OverlayCulprit>,
+        rolled_back: bool,
+        mount_mismatches: Vec<MountMismatch>,
     ) -> Self {
         let start = SystemTime::now();
 
@@ -68,9 +102,46 @@ impl RuntimeState {
             storage_used: storage_info.1,
             storage_percent: storage_info.2,
             zygisksu_enforce,
+            errors,
+            overlay_culprits,
+            rolled_back,
+            mount_mismatches,
         }
     }
 
+    // Nothing of the previous boot is mounted any more, so start from scratch.
+    pub fn record_failure(report: ErrorReport) -> Result<()> {
+        let state = Self {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            pid: std::process::id(),
+            errors: vec![report],
+            ..Self::default()
+        };
+
+        state.save()
+    }
+
+    pub fn diagnose(&self) -> Vec<DiagnosticIssue> {
+        self.mount_mismatches
+            .iter()
+            .map(|mismatch| DiagnosticIssue {
+                level: DiagnosticLevel::Warning,
+                context: mismatch.module_id.clone(),
+                message: format!(
+                    "{} does not show {} after mounting: {}",
+                    mismatch.path.display(),
+                    mismatch.source.display(),
+                    mismatch.reason
+                ),
+                code: Some(ErrorCode::MountVerify),
+                path: Some(mismatch.path.clone()),
+            })
+            .collect()
+    }
+
     pub fn save(&self) -> Result<()> {
         let json = serde_json::to_string_pretty(self)?;
 
@@ -2,29 +2,25 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
 use std::{
-    ffi::CString,
     fs,
     path::{Path, PathBuf},
-    process::Command,
 };
 
 use anyhow::{Context, Result, bail};
-use rustix::{
-    fs::Mode,
-    mount::{UnmountFlags, unmount},
-};
+use rustix::fs::Mode;
 use serde::Serialize;
 use walkdir::WalkDir;
 
-use crate::{core::state::RuntimeState, defs, utils};
+use crate::{
+    conf::config::Config, core::state::RuntimeState, defs, error::HybridError,
+    mount::backend::MountBackend, utils,
+};
 
 #[cfg(any(target_os = "linux", target_os = "android"))]
 use crate::try_umount::send_unmountable;
 
 const DEFAULT_SELINUX_CONTEXT: &str = "u:object_r:system_file:s0";
 
-const SELINUX_XATTR_KEY: &str = "security.selinux";
-
 pub struct StorageHandle {
     pub mount_point: PathBuf,
     pub mode: String,
@@ -32,20 +28,23 @@ pub struct StorageHandle {
 }
 
 impl StorageHandle {
-    pub fn commit(&mut self, disable_umount: bool) -> Result<()> {
+    pub fn commit(&mut self, backend: &dyn MountBackend, disable_umount: bool) -> Result<()> {
         if self.mode == "erofs_staging" {
             let image_path = self
                 .backing_image
                 .as_ref()
                 .context("EROFS backing image path missing")?;
 
-            utils::create_erofs_image(&self.mount_point, image_path)
+            backend
+                .pack_image(&self.mount_point, image_path)
                 .context("Failed to pack EROFS image")?;
 
-            unmount(&self.mount_point, UnmountFlags::DETACH)
+            backend
+                .unmount(&self.mount_point)
                 .context("Failed to unmount staging tmpfs")?;
 
-            utils::mount_erofs_image(image_path, &self.mount_point)
+            backend
+                .mount_packed_image(image_path, &self.mount_point)
                 .context("Failed to mount finalized EROFS image")?;
 
             #[cfg(any(target_os = "linux", target_os = "android"))]
@@ -91,21 +90,20 @@ pub fn get_usage(path: &Path) -> (u64, u64, u8) {
 }
 
 pub fn setup(
+    backend: &dyn MountBackend,
     mnt_base: &Path,
     img_path: &Path,
-    moduledir: &Path,
-    force_ext4: bool,
-    use_erofs: bool,
-    mount_source: &str,
-    disable_umount: bool,
+    config: &Config,
 ) -> Result<StorageHandle> {
+    let mount_source = config.mountsource.as_str();
+
     if utils::is_mounted(mnt_base) {
-        let _ = unmount(mnt_base, UnmountFlags::DETACH);
+        let _ = backend.unmount(mnt_base);
     }
 
     let try_hide = |path: &Path| {
         #[cfg(any(target_os = "linux", target_os = "android"))]
-        if !disable_umount {
+        if !config.disable_umount {
             let _ = send_unmountable(path);
         }
 
@@ -113,10 +111,19 @@ pub fn setup(
         let _ = path;
     };
 
-    if use_erofs && utils::is_erofs_supported() {
+    if config.use_erofs && !utils::is_erofs_supported() {
+        log::warn!("{}, falling back", HybridError::ErofsUnsupported);
+    }
+
+    if config.use_erofs && utils::is_erofs_supported() {
         let erofs_path = img_path.with_extension("erofs");
 
-        utils::mount_tmpfs(mnt_base, mount_source)?;
+        backend
+            .mount_tmpfs(mnt_base, mount_source)
+            .context(HybridError::StorageMount {
+                target: mnt_base.to_path_buf(),
+                fstype: "tmpfs",
+            })?;
 
         try_hide(mnt_base);
 
@@ -131,7 +138,7 @@ pub fn setup(
         });
     }
 
-    if !force_ext4 && try_setup_tmpfs(mnt_base, mount_source)? {
+    if !config.force_ext4 && try_setup_tmpfs(backend, mnt_base, mount_source)? {
         try_hide(mnt_base);
 
         if img_path.exists()
@@ -153,40 +160,47 @@ pub fn setup(
         });
     }
 
-    let handle = setup_ext4_image(mnt_base, img_path, moduledir)?;
+    let handle = setup_ext4_image(backend, mnt_base, img_path, &config.moduledir)?;
 
     try_hide(mnt_base);
 
     Ok(handle)
 }
 
-fn try_setup_tmpfs(target: &Path, mount_source: &str) -> Result<bool> {
-    if utils::mount_tmpfs(target, mount_source).is_ok() {
-        if utils::is_overlay_xattr_supported(target) {
+fn try_setup_tmpfs(backend: &dyn MountBackend, target: &Path, mount_source: &str) -> Result<bool> {
+    if backend.mount_tmpfs(target, mount_source).is_ok() {
+        if backend.supports_overlay_xattr(target) {
             return Ok(true);
         } else {
-            let _ = unmount(target, UnmountFlags::DETACH);
+            let _ = backend.unmount(target);
         }
     }
 
     Ok(false)
 }
 
-fn setup_ext4_image(target: &Path, img_path: &Path, moduledir: &Path) -> Result<StorageHandle> {
+fn setup_ext4_image(
+    backend: &dyn MountBackend,
+    target: &Path,
+    img_path: &Path,
+    moduledir: &Path,
+) -> Result<StorageHandle> {
     if !img_path.exists() {
         if let Some(parent) = img_path.parent() {
             fs::create_dir_all(parent)?;
         }
 
-        create_image(img_path, moduledir).context("Failed to create modules.img")?;
+        create_image(backend, img_path, moduledir)
+            .context(HybridError::ImageCreate(img_path.to_path_buf()))?;
     }
 
-    if utils::mount_image(img_path, target).is_err() {
-        if utils::repair_image(img_path).is_ok() {
-            utils::mount_image(img_path, target)
+    if backend.mount_image(img_path, target).is_err() {
+        if backend.repair_image(img_path).is_ok() {
+            backend
+                .mount_image(img_path, target)
                 .context("Failed to mount modules.img after repair")?;
         } else {
-            bail!("Failed to repair modules.img");
+            bail!(HybridError::ImageRepair(img_path.to_path_buf()));
         }
     }
 
@@ -197,7 +211,7 @@ fn setup_ext4_image(target: &Path, img_path: &Path, moduledir: &Path) -> Result<
     })
 }
 
-fn create_image(path: &Path, moduledir: &Path) -> Result<()> {
+fn create_image(backend: &dyn MountBackend, path: &Path, moduledir: &Path) -> Result<()> {
     let mut total_size: u64 = 0;
 
     if moduledir.exists() {
@@ -216,29 +230,7 @@ fn create_image(path: &Path, moduledir: &Path) -> Result<()> {
 
     let aligned_size = target_raw.div_ceil(GRANULARITY) * GRANULARITY;
 
-    let size_str = format!("{}", aligned_size);
-
-    let status = Command::new("truncate")
-        .arg("-s")
-        .arg(&size_str)
-        .arg(path)
-        .status()?;
-
-    if !status.success() {
-        bail!("Failed to allocate image file");
-    }
-
-    let status = Command::new("mkfs.ext4")
-        .arg("-O")
-        .arg("^has_journal")
-        .arg(path)
-        .status()?;
-
-    if !status.success() {
-        bail!("Failed to format image file");
-    }
-
-    Ok(())
+    backend.create_image(path, aligned_size)
 }
 
 #[allow(dead_code)]
@@ -255,33 +247,11 @@ pub fn finalize_storage_permissions(target: &Path) {
         log::warn!("Failed to chown storage root: {}", e);
     }
 
-    if let Err(e) = set_selinux_context(target, DEFAULT_SELINUX_CONTEXT) {
+    if let Err(e) = utils::lsetfilecon(target, DEFAULT_SELINUX_CONTEXT) {
         log::warn!("Failed to set SELinux context: {}", e);
     }
 }
 
-fn set_selinux_context(path: &Path, context: &str) -> Result<()> {
-    let c_path = CString::new(path.as_os_str().as_encoded_bytes())?;
-
-    let c_val = CString::new(context)?;
-
-    unsafe {
-        let ret = libc::lsetxattr(
-            c_path.as_ptr(),
-            SELINUX_XATTR_KEY.as_ptr() as *const libc::c_char,
-            c_val.as_ptr() as *const libc::c_void,
-            c_val.as_bytes().len(),
-            0,
-        );
-
-        if ret != 0 {
-            bail!("lsetxattr failed");
-        }
-    }
-
-    Ok(())
-}
-
 pub fn print_status() -> Result<()> {
     let state = RuntimeState::load().ok();
 
@@ -331,3 +301,37 @@ pub fn print_status() -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mount::sim::{SimBackend, SimFs};
+
+    #[test]
+    fn erofs_commit_swaps_staging_tmpfs_for_image() -> Result<()> {
+        let sim = SimBackend::new();
+
+        let (staging, image) = (Path::new("/sim/mnt"), Path::new("/sim/modules.erofs"));
+
+        sim.mount_tmpfs(staging, "tmpfs")?;
+
+        let mut handle = StorageHandle {
+            mount_point: staging.to_path_buf(),
+            mode: "erofs_staging".to_string(),
+            backing_image: Some(image.to_path_buf()),
+        };
+
+        handle.commit(&sim, true)?;
+
+        assert_eq!(handle.mode, "erofs");
+
+        assert_eq!(sim.mount_sources(SimFs::Tmpfs, staging), None);
+
+        assert_eq!(
+            sim.mount_sources(SimFs::Image, staging),
+            Some(vec![image.to_path_buf()])
+        );
+
+        Ok(())
+    }
+}
@@ -1,58 +1,462 @@
 // Copyright 2025 Meta-Hybrid Mount Authors
 // SPDX-License-Identifier: GPL-3.0-or-later
 
-use std::{collections::HashSet, fs, path::Path};
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs, io,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use walkdir::WalkDir;
 
 use crate::{
-    core::inventory::{Module, MountMode},
-    defs, utils,
+    core::{
+        contexts::FileContexts,
+        digest,
+        inventory::{Module, MountMode},
+    },
+    defs,
+    error::{self, HybridError},
+    utils,
 };
 
-pub fn perform_sync(modules: &[Module], target_base: &Path) -> Result<()> {
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum EntryKind {
+    Dir,
+    File { hash: String },
+    Symlink { target: PathBuf },
+    Whiteout,
+    // A `.replace` marker, synced as the opaque flag on its directory.
+    Opaque,
+    Special { rdev: u64 },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct SyncEntry {
+    mode: u32,
+    #[serde(default)]
+    uid: u32,
+    #[serde(default)]
+    gid: u32,
+    // Capabilities and labels only change the xattrs, not the content.
+    #[serde(default)]
+    xattrs: Option<String>,
+    size: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+    #[serde(flatten)]
+    kind: EntryKind,
+}
+
+// What the storage copy of a module was built from, keyed by path relative to
+// the module root. Lives next to the copy so it is dropped along with it.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SyncManifest {
+    entries: BTreeMap<PathBuf, SyncEntry>,
+}
+
+#[derive(Debug, Default)]
+struct SyncDelta {
+    added: Vec<PathBuf>,
+    replaced: Vec<PathBuf>,
+    removed: Vec<PathBuf>,
+    unchanged: usize,
+}
+
+impl SyncManifest {
+    fn load(dst: &Path) -> Option<Self> {
+        let content = fs::read_to_string(dst.join(defs::SYNC_MANIFEST_FILE_NAME)).ok()?;
+
+        serde_json::from_str(&content).ok()
+    }
+
+    fn save(&self, dst: &Path) -> Result<()> {
+        fs::write(
+            dst.join(defs::SYNC_MANIFEST_FILE_NAME),
+            serde_json::to_string(self)?,
+        )?;
+
+        Ok(())
+    }
+}
+
+impl SyncDelta {
+    fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.replaced.is_empty() && self.removed.is_empty()
+    }
+}
+
+// Returns the ids of modules whose storage copy changed.
+pub fn perform_sync(modules: &[Module], target_base: &Path, sysroot: &Path) -> Result<Vec<String>> {
     log::info!("Starting smart module sync to {}", target_base.display());
 
     prune_orphaned_modules(modules, target_base)?;
 
-    modules.par_iter().for_each(|module| {
-        if matches!(module.rules.default_mode, MountMode::Magic) {
-            log::debug!("Skipping sync for Magic Mount module: {}", module.id);
+    // Compiling the device's file_contexts is only worth it once something changed.
+    let contexts = OnceLock::new();
+
+    let mut changed: Vec<String> = modules
+        .par_iter()
+        .filter_map(|module| {
+            if !module.rules.wants_mode(&MountMode::Overlay) {
+                log::debug!("Skipping sync for Magic Mount module: {}", module.id);
+
+                return None;
+            }
+
+            if !has_content(module) {
+                return None;
+            }
+
+            let dst = target_base.join(&module.id);
 
-            return;
+            match sync_module(module, &dst).context(HybridError::ModuleSync {
+                module_id: module.id.clone(),
+            }) {
+                Ok(delta) if delta.is_empty() => {
+                    log::debug!("Skipping module: {} (up to date)", module.id);
+
+                    None
+                }
+                Ok(delta) => {
+                    log::info!(
+                        "Synced module {}: {} added, {} replaced, {} removed, {} unchanged",
+                        module.id,
+                        delta.added.len(),
+                        delta.replaced.len(),
+                        delta.removed.len(),
+                        delta.unchanged
+                    );
+
+                    repair_module_contexts(
+                        &dst,
+                        module,
+                        sysroot,
+                        contexts.get_or_init(|| FileContexts::load(sysroot)),
+                    );
+
+                    Some(module.id.clone())
+                }
+                Err(e) => {
+                    log::error!("[{:?}] {:#}", error::code_of(&e), e);
+
+                    // The copy no longer matches the manifest, so rebuild it next time.
+                    let _ = fs::remove_file(dst.join(defs::SYNC_MANIFEST_FILE_NAME));
+
+                    Some(module.id.clone())
+                }
+            }
+        })
+        .collect();
+
+    changed.sort();
+
+    Ok(changed)
+}
+
+fn has_content(module: &Module) -> bool {
+    defs::BUILTIN_PARTITIONS.iter().any(|p| {
+        let part_path = module.source_path.join(p);
+
+        part_path.exists() && has_files_recursive(&part_path)
+    })
+}
+
+fn sync_module(module: &Module, dst: &Path) -> Result<SyncDelta> {
+    let previous = match SyncManifest::load(dst) {
+        Some(manifest) => manifest,
+        None => {
+            // Copies made before manifests existed cannot be diffed.
+            if dst.exists() {
+                fs::remove_dir_all(dst)?;
+            }
+
+            SyncManifest::default()
         }
+    };
 
-        let dst = target_base.join(&module.id);
+    let desired = scan_source(module, &previous)?;
 
-        let has_content = defs::BUILTIN_PARTITIONS.iter().any(|p| {
-            let part_path = module.source_path.join(p);
+    let delta = diff(&previous, &desired, dst);
 
-            part_path.exists() && has_files_recursive(&part_path)
+    if delta.is_empty() {
+        if previous.entries != desired.entries {
+            desired.save(dst)?;
+        }
+
+        return Ok(delta);
+    }
+
+    apply(&module.source_path, dst, &delta, &desired)?;
+
+    desired.save(dst)?;
+
+    Ok(delta)
+}
+
+fn scan_source(module: &Module, previous: &SyncManifest) -> Result<SyncManifest> {
+    let root = &module.source_path;
+
+    let overlay_only = |relative: &Path, is_dir: bool| {
+        if relative.components().count() == 1 && !is_dir {
+            return true;
+        }
+
+        module
+            .rules
+            .selects(&relative.to_string_lossy(), is_dir, &MountMode::Overlay)
+    };
+
+    let mut entries = BTreeMap::new();
+
+    let walker = WalkDir::new(root)
+        .min_depth(1)
+        .into_iter()
+        .filter_entry(|e| {
+            e.path()
+                .strip_prefix(root)
+                .map(|relative| overlay_only(relative, e.file_type().is_dir()))
+                .unwrap_or(false)
         });
 
-        if has_content && should_sync(&module.source_path, &dst) {
-            log::info!("Syncing module: {} (Updated/New)", module.id);
+    for entry in walker {
+        let entry = entry?;
+
+        let relative = entry.path().strip_prefix(root)?.to_path_buf();
+
+        let metadata = entry.path().symlink_metadata()?;
+
+        let file_type = metadata.file_type();
 
-            if dst.exists()
-                && let Err(e) = fs::remove_dir_all(&dst)
+        let kind = if file_type.is_dir() {
+            EntryKind::Dir
+        } else if is_replace_marker(&relative) {
+            EntryKind::Opaque
+        } else if utils::is_whiteout(&metadata) {
+            EntryKind::Whiteout
+        } else if file_type.is_symlink() {
+            EntryKind::Symlink {
+                target: fs::read_link(entry.path())?,
+            }
+        } else if file_type.is_file() {
+            // Only rehash files whose size or mtime moved since the last sync.
+            let hash = match previous.entries.get(&relative) {
+                Some(SyncEntry {
+                    size,
+                    mtime,
+                    mtime_nsec,
+                    kind: EntryKind::File { hash },
+                    ..
+                }) if *size == metadata.size()
+                    && *mtime == metadata.mtime()
+                    && *mtime_nsec == metadata.mtime_nsec() =>
+                {
+                    hash.clone()
+                }
+                _ => digest::hash_file(entry.path())?,
+            };
+
+            EntryKind::File { hash }
+        } else {
+            EntryKind::Special {
+                rdev: metadata.rdev(),
+            }
+        };
+
+        entries.insert(
+            relative,
+            SyncEntry {
+                mode: metadata.mode(),
+                uid: metadata.uid(),
+                gid: metadata.gid(),
+                xattrs: digest::hash_xattrs(entry.path()),
+                size: metadata.size(),
+                mtime: metadata.mtime(),
+                mtime_nsec: metadata.mtime_nsec(),
+                kind,
+            },
+        );
+    }
+
+    // Directories whose whole content was filtered out are not copied.
+    let parents: HashSet<PathBuf> = entries
+        .keys()
+        .filter_map(|path| path.parent().map(Path::to_path_buf))
+        .collect();
+
+    entries.retain(|path, entry| {
+        entry.kind != EntryKind::Dir
+            || parents.contains(path)
+            || fs::read_dir(root.join(path)).is_ok_and(|mut e| e.next().is_none())
+    });
+
+    Ok(SyncManifest { entries })
+}
+
+fn diff(previous: &SyncManifest, desired: &SyncManifest, dst: &Path) -> SyncDelta {
+    let mut delta = SyncDelta::default();
+
+    for (path, entry) in &desired.entries {
+        match previous.entries.get(path) {
+            None => delta.added.push(path.clone()),
+            Some(old)
+                if old.kind != entry.kind
+                    || old.mode != entry.mode
+                    || (old.uid, old.gid) != (entry.uid, entry.gid)
+                    || old.xattrs != entry.xattrs
+                    || !is_intact(&dst.join(path), entry) =>
             {
-                log::warn!("Failed to clean target dir for {}: {}", module.id, e);
+                delta.replaced.push(path.clone())
             }
+            Some(_) => delta.unchanged += 1,
+        }
+    }
 
-            if let Err(e) = utils::sync_dir(&module.source_path, &dst) {
-                log::error!("Failed to sync module {}: {}", module.id, e);
-            } else {
-                repair_module_contexts(&dst, &module.id);
+    delta.removed = previous
+        .entries
+        .keys()
+        .filter(|path| !desired.entries.contains_key(*path))
+        .cloned()
+        .collect();
+
+    delta
+}
+
+// Catches storage copies that were deleted or truncated behind our back.
+fn is_intact(path: &Path, entry: &SyncEntry) -> bool {
+    let Ok(metadata) = path.symlink_metadata() else {
+        // Markers are never copied, so their directory has to carry the flag.
+        return entry.kind == EntryKind::Opaque
+            && path
+                .parent()
+                .is_some_and(|dir| dir.is_dir() && utils::is_overlay_opaque(dir));
+    };
+
+    match entry.kind {
+        EntryKind::Dir => metadata.is_dir(),
+        EntryKind::File { .. } => metadata.is_file() && metadata.len() == entry.size,
+        EntryKind::Symlink { .. } => metadata.is_symlink(),
+        EntryKind::Whiteout => utils::is_whiteout(&metadata),
+        // A marker file left by an older sync still has to become the flag.
+        EntryKind::Opaque => false,
+        EntryKind::Special { .. } => !metadata.is_dir() && !metadata.is_symlink(),
+    }
+}
+
+fn remove_entry(path: &Path) -> io::Result<()> {
+    let result = match path.symlink_metadata() {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(e) => Err(e),
+    };
+
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        other => other,
+    }
+}
+
+fn apply(src: &Path, dst: &Path, delta: &SyncDelta, desired: &SyncManifest) -> Result<()> {
+    utils::ensure_dir_exists(dst)?;
+
+    // Children sort after their parents, so walk removals backwards.
+    for path in delta.removed.iter().rev() {
+        remove_entry(&dst.join(path))
+            .with_context(|| format!("failed to remove {}", path.display()))?;
+
+        if is_replace_marker(path) {
+            set_opaque(dst, path, false)?;
+        }
+    }
+
+    for path in &delta.replaced {
+        let dst_path = dst.join(path);
+
+        // A directory whose metadata changed keeps its content.
+        if desired.entries[path].kind == EntryKind::Dir && dst_path.is_dir() {
+            continue;
+        }
+
+        remove_entry(&dst_path).with_context(|| format!("failed to replace {}", path.display()))?;
+    }
+
+    let mut updates: Vec<&PathBuf> = delta.added.iter().chain(&delta.replaced).collect();
+
+    updates.sort();
+
+    let mut markers = Vec::new();
+
+    for path in updates {
+        let (src_path, dst_path) = (src.join(path), dst.join(path));
+
+        if let Some(parent) = dst_path.parent() {
+            utils::ensure_dir_exists(parent)?;
+        }
+
+        let result = match desired.entries[path].kind {
+            EntryKind::Dir => utils::create_dir_from(&src_path, &dst_path),
+            EntryKind::Opaque => {
+                markers.push(path);
+
+                continue;
             }
-        } else {
-            log::debug!("Skipping module: {}", module.id);
+            _ => utils::copy_entry(&src_path, &dst_path),
+        };
+
+        result.with_context(|| format!("failed to copy {}", path.display()))?;
+    }
+
+    // Writing into a directory bumps its mtime, so restore those last.
+    let touched: HashSet<&Path> = delta
+        .added
+        .iter()
+        .chain(&delta.replaced)
+        .chain(&delta.removed)
+        .flat_map(|path| path.ancestors())
+        .filter(|path| desired.entries.contains_key(*path))
+        .collect();
+
+    for path in touched {
+        if desired.entries[path].kind == EntryKind::Dir {
+            utils::copy_times(&src.join(path), &dst.join(path))?;
         }
-    });
+    }
+
+    // Flags go on last, so a storage without trusted.* support still gets every
+    // file before the error sends the module back for a full resync.
+    for path in markers {
+        set_opaque(dst, path, true)
+            .with_context(|| format!("failed to apply {}", path.display()))?;
+    }
 
     Ok(())
 }
 
+fn is_replace_marker(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name == defs::REPLACE_DIR_FILE_NAME)
+}
+
+// Overlayfs has no notion of `.replace`, so the marker's directory is flagged
+// opaque instead and the marker itself stays out of the storage copy.
+fn set_opaque(dst: &Path, marker: &Path, opaque: bool) -> Result<()> {
+    let Some(dir) = marker.parent().map(|parent| dst.join(parent)) else {
+        return Ok(());
+    };
+
+    if !dir.is_dir() {
+        return Ok(());
+    }
+
+    utils::set_overlay_opaque(&dir, opaque)
+}
+
 fn prune_orphaned_modules(modules: &[Module], target_base: &Path) -> Result<()> {
     if !target_base.exists() {
         return Ok(());
@@ -85,72 +489,99 @@ fn prune_orphaned_modules(modules: &[Module], target_base: &Path) -> Result<()>
     Ok(())
 }
 
-fn should_sync(src: &Path, dst: &Path) -> bool {
-    if !dst.exists() {
-        return true;
-    }
-
-    let src_prop = src.join("module.prop");
-
-    let dst_prop = dst.join("module.prop");
-
-    if !src_prop.exists() || !dst_prop.exists() {
-        return true;
-    }
-
-    match (fs::read(&src_prop), fs::read(&dst_prop)) {
-        (Ok(s), Ok(d)) => s != d,
-        _ => true,
-    }
+struct ContextRepair<'a> {
+    base: &'a Path,
+    source: &'a Path,
+    sysroot: &'a Path,
+    device: &'a FileContexts,
+    overrides: FileContexts,
 }
 
-fn repair_module_contexts(module_root: &Path, module_id: &str) {
+fn repair_module_contexts(
+    module_root: &Path,
+    module: &Module,
+    sysroot: &Path,
+    device: &FileContexts,
+) {
+    let repair = ContextRepair {
+        base: module_root,
+        source: &module.source_path,
+        sysroot,
+        device,
+        overrides: FileContexts::for_module(&module.source_path, &module.rules),
+    };
+
     for part in defs::BUILTIN_PARTITIONS {
         let part_root = module_root.join(part);
 
         if part_root.exists()
-            && let Err(e) = recursive_context_repair(module_root, &part_root)
+            && let Err(e) = repair.apply(&part_root)
         {
-            log::warn!("Context repair failed for {}/{}: {}", module_id, part, e);
+            log::warn!("Context repair failed for {}/{}: {}", module.id, part, e);
         }
     }
 }
 
-fn recursive_context_repair(base: &Path, current: &Path) -> Result<()> {
-    if !current.exists() {
-        return Ok(());
-    }
+impl ContextRepair<'_> {
+    // Module overrides win, then labels the module shipped, then the live file,
+    // the device file_contexts and finally the parent directory.
+    fn apply(&self, current: &Path) -> Result<()> {
+        if !current.exists() {
+            return Ok(());
+        }
 
-    let file_name = current.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        let relative = current.strip_prefix(self.base)?;
 
-    if (file_name == "upperdir" || file_name == "workdir")
-        && let Some(parent) = current.parent()
-        && let Ok(ctx) = utils::lgetfilecon(parent)
-    {
-        let _ = utils::lsetfilecon(current, &ctx);
-    } else {
-        let relative = current.strip_prefix(base)?;
+        let device_path = Path::new("/").join(relative);
 
-        let system_path = Path::new("/").join(relative);
+        let file_type = current.symlink_metadata()?.file_type();
 
-        if system_path.exists() {
-            let _ = utils::copy_path_context(&system_path, current);
-        } else if let Some(parent) = system_path.parent()
-            && parent.exists()
+        let file_name = current.file_name().and_then(|n| n.to_str()).unwrap_or("");
+
+        if let Some(context) = self.overrides.lookup(&device_path, file_type) {
+            let _ = utils::lsetfilecon(current, context);
+        } else if (file_name == "upperdir" || file_name == "workdir")
+            && let Some(parent) = current.parent()
+            && let Ok(ctx) = utils::lgetfilecon(parent)
         {
-            let _ = utils::copy_path_context(parent, current);
+            let _ = utils::lsetfilecon(current, &ctx);
+        } else if !carries_module_context(&self.source.join(relative)) {
+            let system_path = self.sysroot.join(relative);
+
+            if system_path.exists() {
+                let _ = utils::copy_path_context(&system_path, current);
+            } else if let Some(context) = self.device.lookup(&device_path, file_type) {
+                let _ = utils::lsetfilecon(current, context);
+            } else if let Some(parent) = system_path.parent()
+                && parent.exists()
+            {
+                let _ = utils::copy_path_context(parent, current);
+            }
         }
-    }
 
-    if current.is_dir()
-        && let Ok(entries) = fs::read_dir(current)
-    {
-        for entry in entries.flatten() {
-            let _ = recursive_context_repair(base, &entry.path());
+        if current.is_dir()
+            && let Ok(entries) = fs::read_dir(current)
+        {
+            for entry in entries.flatten() {
+                let _ = self.apply(&entry.path());
+            }
         }
+
+        Ok(())
     }
+}
 
-    Ok(())
+// Labels that only say where a file was staged. Anything else was chosen by the
+// module and survives the repair.
+fn carries_module_context(path: &Path) -> bool {
+    const STAGING_CONTEXTS: [&str; 3] = [
+        "u:object_r:system_file:s0",
+        "u:object_r:adb_data_file:s0",
+        "u:object_r:unlabeled:s0",
+    ];
+
+    utils::lgetfilecon(path)
+        .is_ok_and(|ctx| !STAGING_CONTEXTS.contains(&ctx.trim_end_matches('\0')))
 }
 
 fn has_files_recursive(path: &Path) -> bool {
@@ -164,3 +595,223 @@ fn has_files_recursive(path: &Path) -> bool {
 
     false
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt},
+    };
+
+    use anyhow::{Result, ensure};
+
+    use super::perform_sync;
+    use crate::{
+        core::{
+            inventory,
+            tests::{sandboxed, simulated},
+        },
+        utils,
+    };
+
+    #[test]
+    fn sync_applies_only_the_changed_files() {
+        simulated("sync_applies_only_the_changed_files", |fx| {
+            fx.module_file("alpha", "module.prop", "version=1")?;
+
+            fx.module_file("alpha", "system/etc/hosts", "alpha")?;
+
+            fx.module_file("alpha", "system/bin/tool", "tool")?;
+
+            fx.module_file("alpha", "system/lib/libkeep.so", "keep")?;
+
+            let config = fx.config();
+
+            let sync = || -> Result<Vec<String>> {
+                let modules = inventory::scan(&fx.moduledir, &config)?;
+
+                perform_sync(&modules, &fx.storage, &fx.sysroot)
+            };
+
+            let stored = |relative: &str| fx.storage.join("alpha").join(relative);
+
+            ensure!(sync()? == ["alpha"]);
+
+            let kept_inode = fs::metadata(stored("system/lib/libkeep.so"))?.ino();
+
+            ensure!(sync()?.is_empty(), "unchanged module was synced again");
+
+            // Files change without a module.prop bump.
+            fx.module_file("alpha", "system/etc/hosts", "alpha, edited")?;
+
+            fs::remove_file(fx.moduledir.join("alpha/system/bin/tool"))?;
+
+            fx.module_file("alpha", "system/etc/extra", "extra")?;
+
+            ensure!(sync()? == ["alpha"]);
+
+            ensure!(fs::read_to_string(stored("system/etc/hosts"))? == "alpha, edited");
+
+            ensure!(fs::read_to_string(stored("system/etc/extra"))? == "extra");
+
+            ensure!(!stored("system/bin/tool").exists());
+
+            ensure!(fs::metadata(stored("system/lib/libkeep.so"))?.ino() == kept_inode);
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn sync_preserves_owner_mode_and_timestamps() {
+        simulated("sync_preserves_owner_mode_and_timestamps", |fx| {
+            fx.module_file("alpha", "system/bin/daemon", "#!/system/bin/sh")?;
+
+            let source = fx.moduledir.join("alpha/system/bin/daemon");
+
+            let source_dir = fx.moduledir.join("alpha/system/bin");
+
+            // Ownership changes need privileges the test may not have.
+            let _ = std::os::unix::fs::lchown(&source, Some(1000), Some(2000));
+
+            fs::set_permissions(&source, fs::Permissions::from_mode(0o750))?;
+
+            let past = std::time::UNIX_EPOCH + std::time::Duration::from_secs(978_307_200);
+
+            fs::File::options()
+                .write(true)
+                .open(&source)?
+                .set_modified(past)?;
+
+            fs::File::open(&source_dir)?.set_modified(past)?;
+
+            let config = fx.config();
+
+            let modules = inventory::scan(&fx.moduledir, &config)?;
+
+            perform_sync(&modules, &fx.storage, &fx.sysroot)?;
+
+            let expected = fs::metadata(&source)?;
+
+            let copied = fs::metadata(fx.storage.join("alpha/system/bin/daemon"))?;
+
+            ensure!((copied.uid(), copied.gid()) == (expected.uid(), expected.gid()));
+
+            ensure!(copied.mode() & 0o7777 == 0o750);
+
+            ensure!(copied.modified()? == past);
+
+            ensure!(fs::metadata(fx.storage.join("alpha/system/bin"))?.modified()? == past);
+
+            // Metadata-only changes are picked up incrementally, without wiping the
+            // directory's content.
+            let _ = std::os::unix::fs::lchown(&source, Some(1001), Some(2001));
+
+            fs::set_permissions(&source_dir, fs::Permissions::from_mode(0o750))?;
+
+            fs::File::open(&source_dir)?.set_modified(past)?;
+
+            ensure!(perform_sync(&modules, &fx.storage, &fx.sysroot)? == ["alpha"]);
+
+            let expected = fs::metadata(&source)?;
+
+            let copied = fs::metadata(fx.storage.join("alpha/system/bin/daemon"))?;
+
+            ensure!((copied.uid(), copied.gid()) == (expected.uid(), expected.gid()));
+
+            ensure!(fs::metadata(fx.storage.join("alpha/system/bin"))?.mode() & 0o7777 == 0o750);
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn sync_recreates_special_files() {
+        simulated("sync_recreates_special_files", |fx| {
+            fx.module_file("alpha", "system/etc/hosts", "alpha")?;
+
+            let fifo = fx.moduledir.join("alpha/system/etc/pipe");
+
+            rustix::fs::mknodat(
+                rustix::fs::CWD,
+                &fifo,
+                rustix::fs::FileType::Fifo,
+                rustix::fs::Mode::from(0o640),
+                0,
+            )?;
+
+            let modules = inventory::scan(&fx.moduledir, &fx.config())?;
+
+            perform_sync(&modules, &fx.storage, &fx.sysroot)?;
+
+            let copied = fs::symlink_metadata(fx.storage.join("alpha/system/etc/pipe"))?;
+
+            ensure!(copied.file_type().is_fifo(), "fifo was not recreated");
+
+            ensure!(copied.mode() & 0o7777 == 0o640);
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn sync_turns_markers_into_overlay_semantics() {
+        sandboxed(
+            module_path!(),
+            "sync_turns_markers_into_overlay_semantics",
+            |fx| {
+                fx.module_file("alpha", "system/app/Foo/base.apk", "alpha")?;
+
+                fx.module_file("alpha", "system/app/Foo/.replace", "")?;
+
+                fs::create_dir_all(fx.moduledir.join("alpha/system/etc"))?;
+
+                rustix::fs::mknodat(
+                    rustix::fs::CWD,
+                    fx.moduledir.join("alpha/system/etc/hosts"),
+                    rustix::fs::FileType::CharacterDevice,
+                    rustix::fs::Mode::from_raw_mode(0o644),
+                    0,
+                )?;
+
+                let config = fx.config();
+
+                let sync = || -> Result<Vec<String>> {
+                    let modules = inventory::scan(&fx.moduledir, &config)?;
+
+                    perform_sync(&modules, &fx.storage, &fx.sysroot)
+                };
+
+                let stored = |relative: &str| fx.storage.join("alpha").join(relative);
+
+                ensure!(sync()? == ["alpha"]);
+
+                let whiteout = fs::symlink_metadata(stored("system/etc/hosts"))?;
+
+                ensure!(whiteout.file_type().is_char_device() && whiteout.rdev() == 0);
+
+                ensure!(stored("system/app/Foo/base.apk").exists());
+
+                // The marker becomes the opaque flag rather than a file in the lowerdir.
+                ensure!(!stored("system/app/Foo/.replace").exists());
+
+                if utils::is_overlay_xattr_supported(&fx.storage) {
+                    ensure!(utils::is_overlay_opaque(&stored("system/app/Foo")));
+
+                    ensure!(sync()?.is_empty(), "unchanged module was synced again");
+                } else {
+                    // Without trusted.* the flag cannot be set, so every run retries it.
+                    ensure!(sync()? == ["alpha"], "missing opaque flag was not retried");
+                }
+
+                fs::remove_file(fx.moduledir.join("alpha/system/app/Foo/.replace"))?;
+
+                ensure!(sync()? == ["alpha"]);
+
+                ensure!(stored("system/app/Foo/base.apk").exists());
+
+                Ok(())
+            },
+        );
+    }
+}
@@ -0,0 +1,558 @@
+// Copyright 2025 Meta-Hybrid Mount Authors
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{
+    env, fs,
+    os::unix::process::CommandExt,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::Arc,
+};
+
+use anyhow::{Context, Result, ensure};
+use rustix::mount::{MountPropagationFlags, mount_change};
+use walkdir::WalkDir;
+
+use super::{Executed, OryzaEngine, StorageReady, storage::StorageHandle};
+use crate::{
+    conf::config::Config,
+    defs,
+    error::ErrorCode,
+    mount::{
+        backend::SystemBackend,
+        sim::{SimBackend, SimFs},
+    },
+    utils,
+};
+
+const SANDBOX_ENV: &str = "META_HYBRID_SANDBOX";
+
+// Set on hosts without user namespaces to skip sandboxed tests instead of failing.
+const SKIP_SANDBOX_ENV: &str = "META_HYBRID_SKIP_SANDBOX";
+
+const SYSTEM_CONTEXT: &str = "u:object_r:system_file:s0";
+
+// Re-runs a single test in a child that owns fresh user and mount namespaces.
+// unshare(CLONE_NEWUSER) refuses multithreaded callers, so it has to happen
+// between fork and exec rather than inside the test thread. `module` is the
+// caller's module_path!(), which the child needs to find the test again.
+pub(super) fn sandboxed(module: &str, name: &str, body: impl FnOnce(&Fixture) -> Result<()>) {
+    if let Some(root) = env::var_os(SANDBOX_ENV) {
+        let root = PathBuf::from(root);
+
+        let fixture = mount_change(
+            "/",
+            MountPropagationFlags::PRIVATE | MountPropagationFlags::REC,
+        )
+        .context("failed to make mount ns private")
+        .and_then(|_| utils::mount_tmpfs(&root, "meta-hybrid-test"))
+        .and_then(|_| Fixture::new(root))
+        .expect("failed to build fixture");
+
+        if let Err(e) = body(&fixture) {
+            panic!("{:#}", e);
+        }
+
+        return;
+    }
+
+    let root = env::temp_dir().join(format!("meta-hybrid-it-{}-{}", std::process::id(), name));
+
+    let test_path = module
+        .split_once("::")
+        .map(|(_, p)| format!("{}::{}", p, name))
+        .unwrap();
+
+    let uid_map = format!("0 {} 1", unsafe { libc::getuid() }).into_bytes();
+
+    let gid_map = format!("0 {} 1", unsafe { libc::getgid() }).into_bytes();
+
+    let mut cmd = Command::new(env::current_exe().unwrap());
+
+    cmd.args([
+        test_path.as_str(),
+        "--exact",
+        "--nocapture",
+        "--test-threads=1",
+    ])
+    .env(SANDBOX_ENV, &root);
+
+    unsafe {
+        cmd.pre_exec(move || enter_namespaces(&uid_map, &gid_map));
+    }
+
+    let status = cmd.status();
+
+    let _ = fs::remove_dir_all(&root);
+
+    match status {
+        Ok(status) => assert!(status.success(), "sandboxed test {} failed", name),
+        Err(e) if env::var_os(SKIP_SANDBOX_ENV).is_some() => {
+            eprintln!("skipping {}: user namespaces unavailable ({})", name, e)
+        }
+        Err(e) => panic!(
+            "cannot sandbox {}: {} (set {} to skip)",
+            name, e, SKIP_SANDBOX_ENV
+        ),
+    }
+}
+
+// Runs against the mount simulator, so no namespaces or privileges are needed.
+pub(super) fn simulated(name: &str, body: impl FnOnce(&Fixture) -> Result<()>) {
+    let root = env::temp_dir().join(format!("meta-hybrid-sim-{}-{}", std::process::id(), name));
+
+    let result = Fixture::new(root.clone()).and_then(|fixture| body(&fixture));
+
+    let _ = fs::remove_dir_all(&root);
+
+    if let Err(e) = result {
+        panic!("{:#}", e);
+    }
+}
+
+// Runs after fork, so it must not allocate.
+fn enter_namespaces(uid_map: &[u8], gid_map: &[u8]) -> std::io::Result<()> {
+    if unsafe { libc::unshare(libc::CLONE_NEWUSER | libc::CLONE_NEWNS) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    write_proc(c"/proc/self/setgroups", b"deny")?;
+
+    write_proc(c"/proc/self/uid_map", uid_map)?;
+
+    write_proc(c"/proc/self/gid_map", gid_map)
+}
+
+fn write_proc(path: &std::ffi::CStr, data: &[u8]) -> std::io::Result<()> {
+    let fd = unsafe { libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC) };
+
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    let written = unsafe { libc::write(fd, data.as_ptr().cast(), data.len()) };
+
+    unsafe { libc::close(fd) };
+
+    if written < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+pub(super) struct Fixture {
+    pub(super) sysroot: PathBuf,
+    pub(super) moduledir: PathBuf,
+    pub(super) storage: PathBuf,
+}
+
+impl Fixture {
+    fn new(root: PathBuf) -> Result<Self> {
+        let fixture = Self {
+            sysroot: root.join("sysroot"),
+            moduledir: root.join("modules"),
+            storage: root.join("storage"),
+        };
+
+        for part in ["system/bin", "system/etc", "vendor/etc", "product"] {
+            fs::create_dir_all(fixture.sysroot.join(part))?;
+        }
+
+        fs::create_dir_all(&fixture.moduledir)?;
+
+        Ok(fixture)
+    }
+
+    pub(super) fn system_file(&self, relative: &str, content: &str) -> Result<()> {
+        write_file(&self.sysroot.join(relative), content)
+    }
+
+    pub(super) fn module_file(&self, module_id: &str, relative: &str, content: &str) -> Result<()> {
+        write_file(&self.moduledir.join(module_id).join(relative), content)
+    }
+
+    pub(super) fn module_rules(&self, module_id: &str, json: &str) -> Result<()> {
+        self.module_file(module_id, "hybrid_rules.json", json)
+    }
+
+    pub(super) fn config(&self) -> Config {
+        Config {
+            moduledir: self.moduledir.clone(),
+            sysroot: self.sysroot.clone(),
+            disable_umount: true,
+            ..Config::default()
+        }
+    }
+
+    // Skips init_storage: its overlay xattr probe needs trusted.* which a user
+    // namespace cannot set, so the harness provides the tmpfs itself.
+    pub(super) fn boot(&self, config: Config) -> Result<OryzaEngine<Executed>> {
+        // Magic mount copies contexts from the stock tree; labelling is best effort.
+        for entry in WalkDir::new(&self.sysroot).into_iter().flatten() {
+            utils::lsetfilecon(entry.path(), SYSTEM_CONTEXT)?;
+        }
+
+        utils::mount_tmpfs(&self.storage, "meta-hybrid-test")?;
+
+        shadow_temp_root()?;
+
+        let engine = OryzaEngine {
+            config,
+            backend: Arc::new(SystemBackend),
+            state: StorageReady {
+                handle: StorageHandle {
+                    mount_point: self.storage.clone(),
+                    mode: "tmpfs".to_string(),
+                    backing_image: None,
+                },
+            },
+        };
+
+        engine.scan_and_sync()?.generate_plan()?.execute()
+    }
+
+    pub(super) fn simulate(
+        &self,
+        config: Config,
+        backend: Arc<SimBackend>,
+    ) -> Result<OryzaEngine<Executed>> {
+        OryzaEngine::with_backend(config, backend)
+            .init_storage(&self.storage, &self.storage.with_extension("img"))?
+            .scan_and_sync()?
+            .generate_plan()?
+            .execute()
+    }
+
+    pub(super) fn system_path(&self, relative: &str) -> PathBuf {
+        self.sysroot.canonicalize().unwrap().join(relative)
+    }
+
+    pub(super) fn visible(&self, relative: &str) -> Option<String> {
+        fs::read_to_string(self.sysroot.join(relative)).ok()
+    }
+
+    pub(super) fn listing(&self, relative: &str) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(self.sysroot.join(relative))
+            .map(|entries| {
+                entries
+                    .flatten()
+                    .map(|e| e.file_name().to_string_lossy().to_string())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        names.sort();
+
+        names
+    }
+}
+
+fn write_file(path: &Path, content: &str) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    fs::write(path, content)?;
+
+    Ok(())
+}
+
+// Magic mount picks its workdir from the real temp root candidates, so hide the
+// first one that exists behind an empty tmpfs in our private namespace.
+fn shadow_temp_root() -> Result<()> {
+    let candidate = defs::TMPFS_CANDIDATES
+        .iter()
+        .map(Path::new)
+        .find(|p| p.is_dir())
+        .context("no temp root candidate on this host")?;
+
+    utils::mount_tmpfs(candidate, "meta-hybrid-test")
+}
+
+#[test]
+fn overlay_merges_module_files_into_partition() {
+    sandboxed(
+        module_path!(),
+        "overlay_merges_module_files_into_partition",
+        |fx| {
+            fx.system_file("system/bin/stock", "stock")?;
+
+            fx.system_file("system/etc/hosts", "stock")?;
+
+            fx.module_file("alpha", "system/bin/alpha_tool", "alpha")?;
+
+            fx.module_file("beta", "system/etc/hosts", "beta")?;
+
+            let engine = fx.boot(fx.config())?;
+
+            let bin = fx.listing("system/bin");
+
+            ensure!(
+                bin == ["alpha_tool", "stock"],
+                "unexpected system/bin: {:?}",
+                bin
+            );
+
+            ensure!(fx.visible("system/etc/hosts").as_deref() == Some("beta"));
+
+            ensure!(engine.state.result.fallbacks.is_empty());
+
+            ensure!(engine.state.result.overlay_module_ids == ["alpha", "beta"]);
+
+            Ok(())
+        },
+    );
+}
+
+#[test]
+fn priority_decides_overlay_precedence() {
+    sandboxed(
+        module_path!(),
+        "priority_decides_overlay_precedence",
+        |fx| {
+            fx.system_file("system/etc/hosts", "stock")?;
+
+            fx.module_file("alpha", "system/etc/hosts", "alpha")?;
+
+            fx.module_file("beta", "system/etc/hosts", "beta")?;
+
+            let config = Config {
+                priority: vec!["alpha".to_string()],
+                ..fx.config()
+            };
+
+            fx.boot(config)?;
+
+            ensure!(fx.visible("system/etc/hosts").as_deref() == Some("alpha"));
+
+            Ok(())
+        },
+    );
+}
+
+#[test]
+fn magic_rule_routes_module_through_magic_mount() {
+    sandboxed(
+        module_path!(),
+        "magic_rule_routes_module_through_magic_mount",
+        |fx| {
+            fx.system_file("system/bin/stock", "stock")?;
+
+            fx.system_file("system/etc/hosts", "stock")?;
+
+            // Only replaces files: new entries need a tmpfs skeleton labelled from
+            // the stock tree, and desktop hosts rarely carry SELinux labels.
+            fx.module_file("gamma", "system/bin/stock", "gamma")?;
+
+            fx.module_file("gamma", "system/etc/hosts", "gamma")?;
+
+            fx.module_rules("gamma", r#"{"default_mode": "magic"}"#)?;
+
+            let engine = fx.boot(fx.config())?;
+
+            if let Some(error) = &engine.state.result.magic_error {
+                anyhow::bail!("magic mount failed: {}", error.message);
+            }
+
+            ensure!(fx.visible("system/bin/stock").as_deref() == Some("gamma"));
+
+            ensure!(fx.visible("system/etc/hosts").as_deref() == Some("gamma"));
+
+            ensure!(engine.state.result.magic_module_ids == ["gamma"]);
+
+            ensure!(engine.state.result.overlay_module_ids.is_empty());
+
+            Ok(())
+        },
+    );
+}
+
+#[test]
+fn simulated_overlay_failure_falls_back_to_magic() {
+    simulated("overlay_failure_falls_back_to_magic", |fx| {
+        fx.system_file("system/bin/stock", "stock")?;
+
+        fx.module_file("alpha", "system/bin/alpha_tool", "alpha")?;
+
+        let sim = Arc::new(SimBackend::new());
+
+        sim.fail_at(fx.system_path("system"));
+
+        let engine = fx.simulate(fx.config(), sim.clone())?;
+
+        let result = &engine.state.result;
+
+        ensure!(sim.mount_sources(SimFs::Tmpfs, &fx.storage).is_some());
+
+        ensure!(
+            sim.mount_sources(SimFs::Overlay, fx.system_path("system"))
+                .is_none()
+        );
+
+        ensure!(
+            sim.mount_sources(SimFs::Magic, &fx.sysroot) == Some(vec![fx.storage.join("alpha")])
+        );
+
+        ensure!(result.fallbacks.len() == 1 && result.fallbacks[0].partition_name == "system");
+
+        ensure!(result.overlay_module_ids.is_empty());
+
+        ensure!(result.magic_module_ids == ["alpha"]);
+
+        ensure!(
+            sim.source_of(fx.system_path("system/bin/alpha_tool"))
+                == Some(fx.storage.join("alpha/system/bin/alpha_tool"))
+        );
+
+        Ok(())
+    });
+}
+
+#[test]
+fn simulated_overlay_view_follows_priority() {
+    simulated("overlay_view_follows_priority", |fx| {
+        fx.system_file("system/etc/hosts", "stock")?;
+
+        fx.module_file("alpha", "system/etc/hosts", "alpha")?;
+
+        fx.module_file("beta", "system/etc/hosts", "beta")?;
+
+        let sim = Arc::new(SimBackend::new());
+
+        let config = Config {
+            priority: vec!["beta".to_string()],
+            ..fx.config()
+        };
+
+        let engine = fx.simulate(config, sim.clone())?;
+
+        ensure!(
+            sim.mount_sources(SimFs::Overlay, fx.system_path("system"))
+                == Some(vec![
+                    fx.storage.join("beta/system"),
+                    fx.storage.join("alpha/system"),
+                ])
+        );
+
+        ensure!(engine.state.result.fallbacks.is_empty());
+
+        ensure!(
+            sim.source_of(fx.system_path("system/etc/hosts"))
+                == Some(fx.storage.join("beta/system/etc/hosts"))
+        );
+
+        Ok(())
+    });
+}
+
+#[test]
+fn simulated_overlay_failure_isolates_offending_layers() {
+    simulated("overlay_failure_isolates_offending_layers", |fx| {
+        for id in ["alpha", "beta", "gamma", "delta"] {
+            fx.module_file(id, &format!("system/bin/{}_tool", id), id)?;
+        }
+
+        let sim = Arc::new(SimBackend::new());
+
+        sim.fail_at(fx.storage.join("alpha/system"));
+
+        sim.fail_at(fx.storage.join("gamma/system"));
+
+        let engine = fx.simulate(fx.config(), sim.clone())?;
+
+        let result = &engine.state.result;
+
+        ensure!(
+            sim.mount_sources(SimFs::Overlay, fx.system_path("system"))
+                == Some(vec![
+                    fx.storage.join("delta/system"),
+                    fx.storage.join("beta/system"),
+                ])
+        );
+
+        ensure!(result.overlay_module_ids == ["beta", "delta"]);
+
+        ensure!(result.magic_module_ids == ["alpha", "gamma"]);
+
+        ensure!(
+            result.fallbacks.len() == 1
+                && result.fallbacks[0].partial
+                && result.fallbacks[0].module_ids == ["gamma", "alpha"]
+        );
+
+        let culprits: Vec<(&str, &str)> = result
+            .culprits
+            .iter()
+            .map(|c| (c.module_id.as_str(), c.partition.as_str()))
+            .collect();
+
+        ensure!(culprits == [("gamma", "system"), ("alpha", "system")]);
+
+        ensure!(
+            result
+                .culprits
+                .iter()
+                .all(|c| c.error.code == ErrorCode::OverlayMount)
+        );
+
+        ensure!(
+            sim.source_of(fx.system_path("system/bin/gamma_tool"))
+                == Some(fx.storage.join("gamma/system/bin/gamma_tool"))
+        );
+
+        Ok(())
+    });
+}
+
+#[test]
+fn simulated_strict_mode_rolls_back_on_magic_failure() {
+    simulated("strict_mode_rolls_back_on_magic_failure", |fx| {
+        fx.system_file("system/bin/stock", "stock")?;
+
+        fx.module_file("alpha", "system/bin/alpha_tool", "alpha")?;
+
+        fx.module_file("beta", "system/bin/beta_tool", "beta")?;
+
+        fx.module_rules("beta", r#"{"default_mode": "magic"}"#)?;
+
+        let sim = Arc::new(SimBackend::new());
+
+        sim.fail_at(&fx.sysroot);
+
+        let config = Config {
+            strict_mount: true,
+            ..fx.config()
+        };
+
+        let engine = fx.simulate(config, sim.clone())?;
+
+        let result = &engine.state.result;
+
+        ensure!(result.magic_error.is_some());
+
+        let rollback = result
+            .rollback
+            .as_ref()
+            .context("strict mode did not roll back")?;
+
+        ensure!(rollback.detached == [fx.system_path("system")]);
+
+        ensure!(
+            sim.mount_sources(SimFs::Overlay, fx.system_path("system"))
+                .is_none()
+        );
+
+        ensure!(
+            sim.source_of(fx.system_path("system/bin/alpha_tool"))
+                .is_none()
+        );
+
+        ensure!(result.overlay_module_ids.is_empty() && result.magic_module_ids.is_empty());
+
+        // Storage was mounted before the run and stays up.
+        ensure!(sim.mount_sources(SimFs::Tmpfs, &fx.storage).is_some());
+
+        Ok(())
+    });
+}
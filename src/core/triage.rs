@@ -0,0 +1,271 @@
+// Copyright 2025 Meta-Hybrid Mount Authors
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use walkdir::WalkDir;
+
+use crate::{
+    conf::{config, pattern},
+    core::inventory::MountMode,
+    defs,
+    mount::overlay,
+};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ModeDecision {
+    pub mode: MountMode,
+    pub reason: String,
+    // What each `auto` entry of the module's `paths` resolved to.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub paths: BTreeMap<String, ModeDecision>,
+}
+
+fn partitions(config: &config::Config) -> impl Iterator<Item = &str> {
+    defs::BUILTIN_PARTITIONS
+        .iter()
+        .copied()
+        .chain(config.partitions.iter().map(String::as_str))
+}
+
+// Mounts nested inside the partitions. An overlay on the partition root does
+// not reach below them.
+pub fn child_mounts(config: &config::Config) -> Vec<PathBuf> {
+    partitions(config)
+        .flat_map(|part| {
+            let root = config.sysroot.join(part);
+
+            overlay::get_sub_mounts(&root.to_string_lossy()).unwrap_or_default()
+        })
+        .map(PathBuf::from)
+        .collect()
+}
+
+// Routes `auto` through magic mount when the module's layout is known to
+// misbehave under OverlayFS. With a `scope` pattern only the subtree it covers
+// is looked at, otherwise the whole module.
+pub fn select_mode(
+    module_dir: &Path,
+    config: &config::Config,
+    mounts: &[PathBuf],
+    scope: Option<&str>,
+) -> ModeDecision {
+    match find_hazard(module_dir, config, mounts, scope) {
+        Some(reason) => ModeDecision {
+            mode: MountMode::Magic,
+            reason,
+            ..Default::default()
+        },
+        None => ModeDecision {
+            mode: MountMode::Overlay,
+            reason: "auto: no OverlayFS hazards found".to_string(),
+            ..Default::default()
+        },
+    }
+}
+
+fn find_hazard(
+    module_dir: &Path,
+    config: &config::Config,
+    mounts: &[PathBuf],
+    scope: Option<&str>,
+) -> Option<String> {
+    let is_partition = |name: &str| partitions(config).any(|p| p == name);
+
+    let in_scope =
+        |relative: &str| scope.is_none_or(|p| pattern::match_prefix(p, relative).is_some());
+
+    for part in partitions(config) {
+        if scope.is_some_and(|p| !in_scope(part) && !pattern::may_match_below(p, part)) {
+            continue;
+        }
+
+        let module_part = module_dir.join(part);
+
+        let Ok(metadata) = module_part.symlink_metadata() else {
+            continue;
+        };
+
+        if !metadata.is_dir() && in_scope(part) {
+            return Some(format!("auto: replaces the /{} mount point", part));
+        }
+
+        if module_part.join(defs::REPLACE_DIR_FILE_NAME).exists() && in_scope(part) {
+            return Some(format!("auto: replaces the whole /{} partition", part));
+        }
+
+        for entry in WalkDir::new(&module_part)
+            .min_depth(1)
+            .into_iter()
+            .flatten()
+        {
+            let Ok(relative) = entry.path().strip_prefix(module_dir) else {
+                continue;
+            };
+
+            if !in_scope(&relative.to_string_lossy()) {
+                continue;
+            }
+
+            let device_path = Path::new("/").join(relative);
+
+            let target = config.sysroot.join(relative);
+
+            // Partitions such as /system/vendor are symlinks to their own mount
+            // point, which a directory in an overlay layer would shadow.
+            if entry.depth() == 1
+                && is_partition(&entry.file_name().to_string_lossy())
+                && target.is_symlink()
+            {
+                return Some(format!(
+                    "auto: replaces the {} mount point",
+                    device_path.display()
+                ));
+            }
+
+            if let Some(mount) = mounts.iter().find(|m| target.starts_with(m)) {
+                return Some(format!(
+                    "auto: ships {} under the child mount {}",
+                    device_path.display(),
+                    mount.display()
+                ));
+            }
+
+            if entry.file_type().is_dir()
+                && entry.path().join(defs::REPLACE_DIR_FILE_NAME).exists()
+                && mounts.iter().any(|m| m.starts_with(&target))
+            {
+                return Some(format!(
+                    "auto: needs .replace on the mounted subtree {}",
+                    device_path.display()
+                ));
+            }
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::ensure;
+
+    use crate::{
+        core::{
+            inventory::{self, MountMode},
+            planner,
+            tests::sandboxed,
+        },
+        utils,
+    };
+
+    #[test]
+    fn auto_mode_routes_overlay_hazards_to_magic() {
+        sandboxed(
+            module_path!(),
+            "auto_mode_routes_overlay_hazards_to_magic",
+            |fx| {
+                fx.system_file("vendor/etc/sub/stock", "stock")?;
+
+                fx.system_file("vendor/firmware/stock", "stock")?;
+
+                std::os::unix::fs::symlink("../vendor", fx.sysroot.join("system/vendor"))?;
+
+                utils::mount_tmpfs(&fx.sysroot.join("vendor/etc/sub"), "meta-hybrid-test")?;
+
+                utils::mount_tmpfs(&fx.sysroot.join("vendor/firmware"), "meta-hybrid-test")?;
+
+                // alpha is harmless, the rest each trip one of the hazards.
+                fx.module_file("alpha", "system/etc/hosts", "alpha")?;
+
+                fx.module_file("beta", "system/vendor/lib/libbeta.so", "beta")?;
+
+                fx.module_file("gamma", "vendor/firmware/blob.bin", "gamma")?;
+
+                fx.module_file("delta", "vendor/etc/delta.conf", "delta")?;
+
+                fx.module_file("delta", "vendor/etc/.replace", "")?;
+
+                fx.module_file("omega", "vendor/etc/omega.conf", "omega")?;
+
+                // Only the auto pattern that covers the child mount turns to magic.
+                fx.module_file("zeta", "system/etc/zeta.conf", "zeta")?;
+
+                fx.module_file("zeta", "vendor/firmware/zeta.bin", "zeta")?;
+
+                fx.module_rules(
+                    "zeta",
+                    r#"{"paths": {"system/etc": "auto", "vendor/firmware": "auto"}}"#,
+                )?;
+
+                for id in ["alpha", "beta", "gamma", "delta"] {
+                    fx.module_rules(id, r#"{"default_mode": "auto"}"#)?;
+                }
+
+                let config = fx.config();
+
+                let modules = inventory::scan(&fx.moduledir, &config)?;
+
+                let plan = planner::generate(&config, &modules, &fx.moduledir)?;
+
+                let decisions: Vec<(&str, MountMode, &str)> = plan
+                    .mode_decisions
+                    .iter()
+                    .map(|(id, d)| (id.as_str(), d.mode.clone(), d.reason.as_str()))
+                    .collect();
+
+                let expected = [
+                    ("alpha", MountMode::Overlay, "no OverlayFS hazards"),
+                    (
+                        "beta",
+                        MountMode::Magic,
+                        "replaces the /system/vendor mount point",
+                    ),
+                    (
+                        "delta",
+                        MountMode::Magic,
+                        ".replace on the mounted subtree /vendor/etc",
+                    ),
+                    ("gamma", MountMode::Magic, "under the child mount"),
+                    ("omega", MountMode::Overlay, "default"),
+                    ("zeta", MountMode::Overlay, "default"),
+                ];
+
+                ensure!(
+                    decisions.len() == expected.len()
+                        && decisions
+                            .iter()
+                            .zip(&expected)
+                            .all(|(d, e)| d.0 == e.0 && d.1 == e.1 && d.2.contains(e.2)),
+                    "unexpected decisions: {:?}",
+                    decisions
+                );
+
+                let zeta: Vec<(&str, MountMode)> = plan.mode_decisions["zeta"]
+                    .paths
+                    .iter()
+                    .map(|(p, d)| (p.as_str(), d.mode.clone()))
+                    .collect();
+
+                ensure!(
+                    zeta == [
+                        ("system/etc", MountMode::Overlay),
+                        ("vendor/firmware", MountMode::Magic)
+                    ],
+                    "unexpected path decisions: {:?}",
+                    zeta
+                );
+
+                ensure!(plan.magic_module_ids == ["beta", "delta", "gamma", "zeta"]);
+
+                ensure!(plan.overlay_module_ids == ["alpha", "omega", "zeta"]);
+
+                Ok(())
+            },
+        );
+    }
+}
@@ -0,0 +1,353 @@
+// Copyright 2025 Meta-Hybrid Mount Authors
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, Read},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use rayon::prelude::*;
+use walkdir::WalkDir;
+
+use crate::{
+    conf::config::Config,
+    core::{
+        executor::ExecutionResult,
+        planner::{MountPlan, OverlayOperation},
+        state::MountMismatch,
+    },
+    defs,
+    mount::{
+        magic::{self, MagicRequest},
+        node::{Node, NodeFileType},
+    },
+    utils,
+};
+
+// What a live path should show. `source` is None where a module deleted it.
+struct Claim {
+    module_id: String,
+    source: Option<PathBuf>,
+    // Overlayfs reports its own st_dev, so only the inode can be matched.
+    overlay: bool,
+}
+
+type Claims = BTreeMap<PathBuf, Claim>;
+
+// Walks every file the modules contributed and checks that its live path shows
+// the module's copy: the same inode, or failing that the same bytes.
+pub fn verify(plan: &MountPlan, result: &ExecutionResult, config: &Config) -> Vec<MountMismatch> {
+    verify_at(plan, result, config, Path::to_path_buf)
+}
+
+// `live_path` maps a path to where reads of it end up, the tests resolve it
+// against the simulator's views.
+fn verify_at(
+    plan: &MountPlan,
+    result: &ExecutionResult,
+    config: &Config,
+    live_path: impl Fn(&Path) -> PathBuf + Sync,
+) -> Vec<MountMismatch> {
+    if result.rollback.is_some() {
+        return Vec::new();
+    }
+
+    let mut claims = Claims::new();
+
+    claim_overlays(&mut claims, plan, result);
+
+    if let Err(e) = claim_magic(&mut claims, plan, result, config) {
+        log::warn!("Cannot rebuild the magic mount tree to verify: {:#}", e);
+    }
+
+    claims
+        .par_iter()
+        .filter_map(|(path, claim)| {
+            let source = claim.source.as_ref()?;
+
+            let reason = compare(&live_path(path), source, claim.overlay)?;
+
+            Some(MountMismatch {
+                module_id: claim.module_id.clone(),
+                path: path.clone(),
+                source: source.clone(),
+                reason,
+            })
+        })
+        .collect()
+}
+
+fn claim_overlays(claims: &mut Claims, plan: &MountPlan, result: &ExecutionResult) {
+    for op in &plan.overlay_ops {
+        let fallback = result
+            .fallbacks
+            .iter()
+            .find(|f| f.partition_name == op.partition_name);
+
+        if fallback.is_some_and(|f| !f.partial) {
+            continue;
+        }
+
+        let target = Path::new(&op.target);
+
+        // Whiteouts and opaque directories hide everything in the layers below.
+        let mut hidden: Vec<PathBuf> = Vec::new();
+
+        for layer in &op.lowerdirs {
+            let module_id = OverlayOperation::layer_module_id(layer);
+
+            if fallback.is_some_and(|f| f.module_ids.contains(&module_id)) {
+                continue;
+            }
+
+            let mut hides = Vec::new();
+
+            for entry in WalkDir::new(layer).min_depth(1).into_iter().flatten() {
+                let Ok(relative) = entry.path().strip_prefix(layer) else {
+                    continue;
+                };
+
+                let path = target.join(relative);
+
+                if hidden.iter().any(|dir| path.starts_with(dir)) {
+                    continue;
+                }
+
+                if entry.file_type().is_dir() {
+                    if utils::is_overlay_opaque(entry.path()) {
+                        hides.push(path);
+                    }
+
+                    continue;
+                }
+
+                if entry.file_name() == defs::REPLACE_DIR_FILE_NAME {
+                    continue;
+                }
+
+                let whiteout = entry.metadata().is_ok_and(|m| utils::is_whiteout(&m));
+
+                if whiteout {
+                    hides.push(path.clone());
+                }
+
+                claims.entry(path).or_insert(Claim {
+                    module_id: module_id.clone(),
+                    source: (!whiteout).then(|| entry.path().to_path_buf()),
+                    overlay: true,
+                });
+            }
+
+            hidden.extend(hides);
+        }
+
+        for sel in plan
+            .forced_selections
+            .iter()
+            .filter(|sel| sel.partition_name == op.partition_name)
+        {
+            claims.insert(
+                sel.target.clone(),
+                Claim {
+                    module_id: sel.module_id.clone(),
+                    source: Some(sel.source.clone()),
+                    overlay: false,
+                },
+            );
+        }
+    }
+}
+
+// Magic mount goes on top of the overlays, so its claims replace theirs.
+fn claim_magic(
+    claims: &mut Claims,
+    plan: &MountPlan,
+    result: &ExecutionResult,
+    config: &Config,
+) -> Result<()> {
+    if result.magic_paths.is_empty() {
+        return Ok(());
+    }
+
+    let Some(root) = magic::build_tree(&MagicRequest {
+        tmp_path: Path::new(""),
+        module_paths: &result.magic_paths,
+        sysroot: &config.sysroot,
+        mount_source: &config.mountsource,
+        extra_partitions: &config.partitions,
+        exclusions: result.magic_exclusions.clone(),
+        module_rules: &plan.magic_rules,
+        disable_umount: config.disable_umount,
+    })?
+    else {
+        return Ok(());
+    };
+
+    claim_node(claims, &root, &config.sysroot, &result.magic_paths);
+
+    Ok(())
+}
+
+fn claim_node(claims: &mut Claims, node: &Node, path: &Path, roots: &[PathBuf]) {
+    if node.skip {
+        return;
+    }
+
+    match (node.file_type, &node.module_path) {
+        (NodeFileType::Whiteout, _) => claims.retain(|claimed, _| !claimed.starts_with(path)),
+        (NodeFileType::Directory, _) => {
+            if node.replace {
+                claims.retain(|claimed, _| !claimed.starts_with(path));
+            }
+
+            for child in node.children.values() {
+                claim_node(claims, child, &path.join(&child.name), roots);
+            }
+        }
+        (_, Some(source)) => {
+            let module_id = roots
+                .iter()
+                .find(|root| source.starts_with(root))
+                .and_then(|root| root.file_name())
+                .map(|s| s.to_string_lossy().to_string())
+                .unwrap_or_else(|| "UNKNOWN".into());
+
+            claims.insert(
+                path.to_path_buf(),
+                Claim {
+                    module_id,
+                    source: Some(source.clone()),
+                    overlay: false,
+                },
+            );
+        }
+        _ => {}
+    }
+}
+
+// Returns why `live` does not show `source`, if it doesn't. Content is only
+// read when the inode does not match, and then compared byte by byte.
+fn compare(live: &Path, source: &Path, overlay: bool) -> Option<String> {
+    let expected = fs::symlink_metadata(source).ok()?;
+
+    let actual = match fs::symlink_metadata(live) {
+        Ok(metadata) => metadata,
+        Err(e) => return Some(format!("not visible ({})", e)),
+    };
+
+    if expected.is_symlink() {
+        let wanted = fs::read_link(source).ok();
+
+        let found = fs::read_link(live).ok();
+
+        return (wanted != found).then(|| match found {
+            Some(found) => format!("symlink points to {}", found.display()),
+            None => "not a symlink".to_string(),
+        });
+    }
+
+    if actual.ino() == expected.ino() && (overlay || actual.dev() == expected.dev()) {
+        return None;
+    }
+
+    if !actual.is_file() {
+        return Some("not a regular file".to_string());
+    }
+
+    if actual.len() != expected.len() {
+        return Some("content differs".to_string());
+    }
+
+    match same_content(live, source) {
+        Ok(true) => None,
+        Ok(false) => Some("content differs".to_string()),
+        Err(e) => Some(format!("cannot compare content: {}", e)),
+    }
+}
+
+fn same_content(a: &Path, b: &Path) -> io::Result<bool> {
+    let (mut a, mut b) = (fs::File::open(a)?, fs::File::open(b)?);
+
+    let (mut buf_a, mut buf_b) = (vec![0u8; 64 * 1024], vec![0u8; 64 * 1024]);
+
+    loop {
+        let read = a.read(&mut buf_a)?;
+
+        if read == 0 {
+            return Ok(b.read(&mut buf_b[..1])? == 0);
+        }
+
+        b.read_exact(&mut buf_b[..read])?;
+
+        if buf_a[..read] != buf_b[..read] {
+            return Ok(false);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use anyhow::ensure;
+
+    use super::verify_at;
+    use crate::{
+        core::tests::simulated,
+        mount::{backend::MountBackend, sim::SimBackend},
+    };
+
+    #[test]
+    fn simulated_verification_flags_files_that_are_not_visible() {
+        simulated("verification_flags_files_that_are_not_visible", |fx| {
+            fx.system_file("system/bin/alpha_tool", "stock")?;
+
+            fx.module_file("alpha", "system/bin/alpha_tool", "alpha")?;
+
+            fx.module_file("beta", "system/etc/beta.conf", "beta")?;
+
+            fx.module_rules("beta", r#"{"default_mode": "magic"}"#)?;
+
+            let sim = Arc::new(SimBackend::new());
+
+            let config = fx.config();
+
+            let engine = fx.simulate(config.clone(), sim.clone())?;
+
+            let verify = || {
+                verify_at(&engine.state.plan, &engine.state.result, &config, |path| {
+                    sim.source_of(path).unwrap_or_else(|| path.to_path_buf())
+                })
+            };
+
+            ensure!(verify().is_empty());
+
+            // Detaching the overlay brings the stock file back and takes the magic
+            // mounted one with it.
+            sim.unmount(&fx.system_path("system"))?;
+
+            let mismatches = verify();
+
+            ensure!(mismatches.len() == 2);
+
+            ensure!(
+                mismatches[0].module_id == "alpha"
+                    && mismatches[0].path == fx.system_path("system/bin/alpha_tool")
+                    && mismatches[0].source == fx.storage.join("alpha/system/bin/alpha_tool")
+                    && mismatches[0].reason == "content differs"
+            );
+
+            ensure!(
+                mismatches[1].module_id == "beta"
+                    && mismatches[1].path == fx.system_path("system/etc/beta.conf")
+                    && mismatches[1].reason.starts_with("not visible")
+            );
+
+            Ok(())
+        });
+    }
+}
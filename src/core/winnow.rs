@@ -1,11 +1,21 @@
 // Copyright 2025 Meta-Hybrid Mount Authors
 // SPDX-License-Identifier: GPL-3.0-or-later
 
-use std::path::{Path, PathBuf};
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+};
 
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 
-use crate::{conf::config::WinnowingTable, core::planner::ConflictEntry};
+use crate::{
+    conf::config::WinnowingTable,
+    core::planner::{
+        ConflictEntry, ConflictKind, ConflictScope, ContentVerdict, ForcedSelection, MountPlan,
+        OverlayOperation, conflict_kind,
+    },
+};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ChaffConflict {
@@ -13,6 +23,16 @@ pub struct ChaffConflict {
     pub contenders: Vec<String>,
     pub selected: String,
     pub is_forced: bool,
+    #[serde(default)]
+    pub matched_rule: Option<String>,
+    #[serde(default)]
+    pub effective_order: Vec<String>,
+    #[serde(default)]
+    pub scope: ConflictScope,
+    #[serde(default)]
+    pub kind: ConflictKind,
+    #[serde(default)]
+    pub verdict: ContentVerdict,
 }
 
 pub fn sift_conflicts(conflicts: Vec<ConflictEntry>, table: &WinnowingTable) -> Vec<ChaffConflict> {
@@ -21,30 +41,459 @@ pub fn sift_conflicts(conflicts: Vec<ConflictEntry>, table: &WinnowingTable) ->
         .map(|c| {
             let path_str = format!("/{}/{}", c.partition, c.relative_path);
 
-            let forced_module = table.get_preferred_module(Path::new(&path_str));
-
-            let selected = if let Some(forced) = &forced_module {
-                if c.contending_modules.contains(forced) {
-                    forced.clone()
-                } else {
-                    c.contending_modules
-                        .last()
-                        .unwrap_or(&"unknown".to_string())
-                        .clone()
-                }
-            } else {
-                c.contending_modules
-                    .last()
-                    .unwrap_or(&"unknown".to_string())
-                    .clone()
+            // Forced binds run before Magic Mount, which would cover them again,
+            // so rules only decide conflicts that stay within OverlayFS.
+            // A rule naming a module that doesn't provide the path decides nothing.
+            let forced_module = match c.scope {
+                ConflictScope::Overlay => table
+                    .get_preferred_module(Path::new(&path_str))
+                    .filter(|forced| c.contending_modules.contains(&forced.module_id)),
+                _ => None,
+            };
+
+            // Contenders are listed in lowerdir order, so the first one is the
+            // layer OverlayFS actually serves unless a rule overrides it.
+            let selected = match &forced_module {
+                Some(forced) => forced.module_id.clone(),
+                None => c
+                    .contending_modules
+                    .first()
+                    .cloned()
+                    .unwrap_or_else(|| "unknown".to_string()),
             };
 
+            let mut effective_order = vec![selected.clone()];
+
+            effective_order.extend(
+                c.contending_modules
+                    .iter()
+                    .filter(|id| **id != selected)
+                    .cloned(),
+            );
+
             ChaffConflict {
                 path: PathBuf::from(path_str),
                 contenders: c.contending_modules,
                 selected,
                 is_forced: forced_module.is_some(),
+                matched_rule: forced_module.map(|f| f.pattern),
+                effective_order,
+                scope: c.scope,
+                kind: c.kind,
+                verdict: c.verdict,
             }
         })
         .collect()
 }
+
+struct Precedence {
+    winner: usize,
+    loser: usize,
+}
+
+// Reorders overlay layers so forced modules sit above the ones they beat.
+// Contradicting rules cannot be solved by ordering and fall back to file binds.
+pub fn enforce_selections(plan: &mut MountPlan, table: &WinnowingTable) {
+    if table.rules.is_empty() {
+        return;
+    }
+
+    let forced: Vec<ForcedSelection> = plan
+        .overlay_ops
+        .par_iter_mut()
+        .flat_map(|op| enforce_on_operation(op, table))
+        .collect();
+
+    plan.forced_selections.extend(forced);
+}
+
+struct Ruling {
+    rel_path: String,
+    pattern: String,
+    winner: usize,
+    layers: Vec<usize>,
+    kind: ConflictKind,
+}
+
+fn enforce_on_operation(op: &mut OverlayOperation, table: &WinnowingTable) -> Vec<ForcedSelection> {
+    let entry_map = op.index_layer_entries();
+
+    let mut rulings: Vec<Ruling> = Vec::new();
+
+    let mut unruled: Vec<(String, Vec<usize>)> = Vec::new();
+
+    for (rel_path, layers) in entry_map {
+        if layers.len() < 2 {
+            continue;
+        }
+
+        let path_str = format!("/{}/{}", op.partition_name, rel_path);
+
+        let Some(forced) = table.get_preferred_module(Path::new(&path_str)) else {
+            unruled.push((rel_path, layers));
+
+            continue;
+        };
+
+        let paths: Vec<PathBuf> = layers
+            .iter()
+            .map(|&i| op.lowerdirs[i].join(&rel_path))
+            .collect();
+
+        // Directories that merge cleanly leave nothing to decide.
+        let Some(kind) = conflict_kind(&paths) else {
+            continue;
+        };
+
+        let Some(&winner) = layers
+            .iter()
+            .find(|&&i| OverlayOperation::layer_module_id(&op.lowerdirs[i]) == forced.module_id)
+        else {
+            log::warn!(
+                "Winnowing rule '{}' for {} names '{}', which does not provide it",
+                forced.pattern,
+                path_str,
+                forced.module_id
+            );
+
+            unruled.push((rel_path, layers));
+
+            continue;
+        };
+
+        rulings.push(Ruling {
+            rel_path,
+            pattern: forced.pattern,
+            winner,
+            layers,
+            kind,
+        });
+    }
+
+    if rulings.is_empty() {
+        return Vec::new();
+    }
+
+    rulings.sort_by(|a, b| a.rel_path.cmp(&b.rel_path));
+
+    let edges: Vec<Precedence> = rulings
+        .iter()
+        .flat_map(|ruling| {
+            ruling
+                .layers
+                .iter()
+                .filter(|&&l| l != ruling.winner)
+                .map(|&loser| Precedence {
+                    winner: ruling.winner,
+                    loser,
+                })
+        })
+        .collect();
+
+    let mut order = stable_layer_order(op.lowerdirs.len(), &edges);
+
+    let mut rank: Vec<usize> = {
+        let mut rank = vec![0; order.len()];
+
+        for (pos, &idx) in order.iter().enumerate() {
+            rank[idx] = pos;
+        }
+
+        rank
+    };
+
+    // Moving whole layers also hands over every other path they share, so the
+    // module order only gives way when no unruled conflict changes hands.
+    let disturbed = unruled.iter().find(|(rel_path, layers)| {
+        let top = layers.iter().min_by_key(|&&l| rank[l]);
+
+        let paths: Vec<PathBuf> = layers
+            .iter()
+            .map(|&i| op.lowerdirs[i].join(rel_path))
+            .collect();
+
+        top != layers.first() && conflict_kind(&paths).is_some()
+    });
+
+    if let Some((rel_path, _)) = disturbed {
+        log::info!(
+            "Winnowing: keeping the layer order of {}, reordering would also change who serves {}",
+            op.partition_name,
+            Path::new(&op.target).join(rel_path).display()
+        );
+
+        order = (0..op.lowerdirs.len()).collect();
+
+        rank = order.clone();
+    }
+
+    let mut unsatisfied = Vec::new();
+
+    for ruling in &rulings {
+        let top = ruling.layers.iter().min_by_key(|&&l| rank[l]).copied();
+
+        if top == Some(ruling.winner) {
+            continue;
+        }
+
+        let target = Path::new(&op.target).join(&ruling.rel_path);
+
+        // A file bind can only stand in for a regular file.
+        if ruling.kind != ConflictKind::Content {
+            log::warn!(
+                "Winnowing: rule '{}' cannot be enforced on {}, only regular files can be pinned",
+                ruling.pattern,
+                target.display()
+            );
+
+            continue;
+        }
+
+        let winner_layer = &op.lowerdirs[ruling.winner];
+
+        unsatisfied.push(ForcedSelection {
+            partition_name: op.partition_name.clone(),
+            module_id: OverlayOperation::layer_module_id(winner_layer),
+            target,
+            source: winner_layer.join(&ruling.rel_path),
+        });
+    }
+
+    if order.iter().enumerate().any(|(pos, &idx)| pos != idx) {
+        log::info!(
+            "Winnowing: reordered layers of {} to honour forced selections",
+            op.partition_name
+        );
+
+        let layers = std::mem::take(&mut op.lowerdirs);
+
+        op.lowerdirs = order.iter().map(|&i| layers[i].clone()).collect();
+    }
+
+    for sel in &unsatisfied {
+        log::warn!(
+            "Winnowing: layer order cannot honour {}, pinning '{}' with a file bind",
+            sel.target.display(),
+            sel.module_id
+        );
+    }
+
+    unsatisfied
+}
+
+fn stable_layer_order(count: usize, edges: &[Precedence]) -> Vec<usize> {
+    let mut in_degree = vec![0usize; count];
+
+    let mut successors: Vec<BTreeSet<usize>> = vec![BTreeSet::new(); count];
+
+    for edge in edges {
+        if successors[edge.winner].insert(edge.loser) {
+            in_degree[edge.loser] += 1;
+        }
+    }
+
+    let mut ready: BTreeSet<usize> = (0..count).filter(|&i| in_degree[i] == 0).collect();
+
+    let mut order = Vec::with_capacity(count);
+
+    // Lowest index first keeps the original order wherever no rule applies,
+    // cycles are broken the same way.
+
+    let mut placed = vec![false; count];
+
+    while order.len() < count {
+        let next = match ready.pop_first() {
+            Some(idx) => idx,
+            None => match (0..count).find(|&i| !placed[i]) {
+                Some(idx) => idx,
+                None => break,
+            },
+        };
+
+        if placed[next] {
+            continue;
+        }
+
+        placed[next] = true;
+
+        order.push(next);
+
+        for &succ in &successors[next] {
+            in_degree[succ] = in_degree[succ].saturating_sub(1);
+
+            if in_degree[succ] == 0 && !placed[succ] {
+                ready.insert(succ);
+            }
+        }
+    }
+
+    order
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use super::*;
+
+    // One `system` layer per module, each shipping the given files.
+    fn layers(name: &str, modules: &[(&str, &[&str])]) -> (PathBuf, OverlayOperation) {
+        let root = env::temp_dir().join(format!(
+            "meta-hybrid-winnow-{}-{}",
+            std::process::id(),
+            name
+        ));
+
+        let mut lowerdirs = Vec::new();
+
+        for (module_id, files) in modules {
+            let layer = root.join(module_id).join("system");
+
+            for file in *files {
+                let path = layer.join(file);
+
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+
+                fs::write(path, module_id).unwrap();
+            }
+
+            lowerdirs.push(layer);
+        }
+
+        let op = OverlayOperation {
+            partition_name: "system".to_string(),
+            target: "/system".to_string(),
+            lowerdirs,
+        };
+
+        (root, op)
+    }
+
+    fn table(rules: &[(&str, &str)]) -> WinnowingTable {
+        let mut table = WinnowingTable::default();
+
+        for (path, module_id) in rules {
+            table.set_rule(path, module_id);
+        }
+
+        table
+    }
+
+    fn module_order(op: &OverlayOperation) -> Vec<String> {
+        op.lowerdirs
+            .iter()
+            .map(|l| OverlayOperation::layer_module_id(l))
+            .collect()
+    }
+
+    #[test]
+    fn rule_for_a_non_contender_is_not_reported_as_forced() {
+        let conflict = ConflictEntry {
+            partition: "system".to_string(),
+            relative_path: "etc/hosts".to_string(),
+            contending_modules: vec!["a".to_string(), "b".to_string()],
+            scope: ConflictScope::Overlay,
+            kind: ConflictKind::Content,
+            verdict: ContentVerdict::Divergent,
+        };
+
+        let sifted = sift_conflicts(vec![conflict], &table(&[("/system/etc/hosts", "c")]));
+
+        assert_eq!(sifted[0].selected, "a");
+
+        assert!(!sifted[0].is_forced);
+
+        assert_eq!(sifted[0].matched_rule, None);
+    }
+
+    #[test]
+    fn layer_order_is_kept_without_rules() {
+        assert_eq!(stable_layer_order(3, &[]), [0, 1, 2]);
+    }
+
+    #[test]
+    fn forced_module_moves_above_the_layers_it_beats() {
+        let (root, mut op) = layers(
+            "reorder",
+            &[
+                ("a", &["etc/hosts"]),
+                ("b", &["etc/hosts"]),
+                ("c", &["etc/hosts"]),
+            ],
+        );
+
+        let pins = enforce_on_operation(&mut op, &table(&[("/system/etc/hosts", "c")]));
+
+        let _ = fs::remove_dir_all(root);
+
+        assert!(pins.is_empty());
+
+        assert_eq!(module_order(&op), ["c", "a", "b"]);
+    }
+
+    #[test]
+    fn rules_on_symlinks_reorder_layers_too() {
+        let (root, mut op) = layers("symlink", &[("a", &["bin/a"]), ("b", &["bin/b"])]);
+
+        for module_id in ["a", "b"] {
+            std::os::unix::fs::symlink(module_id, root.join(module_id).join("system/bin/sh"))
+                .unwrap();
+        }
+
+        let pins = enforce_on_operation(&mut op, &table(&[("/system/bin/sh", "b")]));
+
+        let _ = fs::remove_dir_all(root);
+
+        assert!(pins.is_empty());
+
+        assert_eq!(module_order(&op), ["b", "a"]);
+    }
+
+    #[test]
+    fn unruled_conflicts_keep_the_layer_order() {
+        let (root, mut op) = layers(
+            "unruled",
+            &[("a", &["etc/x", "etc/y"]), ("b", &["etc/x", "etc/y"])],
+        );
+
+        let pins = enforce_on_operation(&mut op, &table(&[("/system/etc/x", "b")]));
+
+        let _ = fs::remove_dir_all(&root);
+
+        // Moving b up would hand it etc/y as well.
+        assert_eq!(module_order(&op), ["a", "b"]);
+
+        assert_eq!(pins.len(), 1);
+
+        assert_eq!(pins[0].target, Path::new("/system/etc/x"));
+
+        assert_eq!(pins[0].source, root.join("b/system/etc/x"));
+    }
+
+    #[test]
+    fn contradicting_rules_fall_back_to_a_pin() {
+        let (root, mut op) = layers(
+            "contradiction",
+            &[("a", &["etc/x", "etc/y"]), ("b", &["etc/x", "etc/y"])],
+        );
+
+        let pins = enforce_on_operation(
+            &mut op,
+            &table(&[("/system/etc/x", "b"), ("/system/etc/y", "a")]),
+        );
+
+        let _ = fs::remove_dir_all(&root);
+
+        // The cycle is broken by keeping the original order, so only b's pick
+        // is left to a file bind.
+        assert_eq!(module_order(&op), ["a", "b"]);
+
+        assert_eq!(pins.len(), 1);
+
+        assert_eq!(pins[0].module_id, "b");
+
+        assert_eq!(pins[0].target, Path::new("/system/etc/x"));
+
+        assert_eq!(pins[0].source, root.join("b/system/etc/x"));
+    }
+}
@@ -9,6 +9,10 @@ pub const RUN_DIR: &str = "/data/adb/meta-hybrid/run/";
 
 pub const STATE_FILE: &str = "/data/adb/meta-hybrid/run/daemon_state.json";
 
+pub const MANIFEST_FILE: &str = "/data/adb/meta-hybrid/run/mount_manifest.json";
+
+pub const HASH_CACHE_FILE: &str = "/data/adb/meta-hybrid/hash_cache.json";
+
 pub const DAEMON_LOG_FILE: &str = "/data/adb/meta-hybrid/daemon.log";
 
 pub const DISABLE_FILE_NAME: &str = "disable";
@@ -17,6 +21,10 @@ pub const REMOVE_FILE_NAME: &str = "remove";
 
 pub const SKIP_MOUNT_FILE_NAME: &str = "skip_mount";
 
+pub const MODULE_FILE_CONTEXTS: &str = "file_contexts";
+
+pub const SYNC_MANIFEST_FILE_NAME: &str = ".sync_manifest.json";
+
 pub const OVERLAY_SOURCE: &str = "KSU";
 
 pub const KSU_OVERLAY_SOURCE: &str = OVERLAY_SOURCE;
@@ -38,10 +46,8 @@ pub const BUILTIN_PARTITIONS: &[&str] = &[
     "apex",
 ];
 
-#[allow(dead_code)]
 pub const REPLACE_DIR_FILE_NAME: &str = ".replace";
 
-#[allow(dead_code)]
 pub const REPLACE_DIR_XATTR: &str = "trusted.overlay.opaque";
 
 pub const TMPFS_CANDIDATES: &[&str] = &["/debug_ramdisk", "/patch_hw", "/oem", "/root", "/sbin"];
@@ -0,0 +1,184 @@
+// Copyright 2025 Meta-Hybrid Mount Authors
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{fmt, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+// Codes are part of the CLI and state file contract: never rename or reuse one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    #[default]
+    Unknown,
+    StorageMount,
+    ImageCreate,
+    ImageRepair,
+    ErofsUnsupported,
+    ErofsPack,
+    LowerdirTooLong,
+    OverlayMount,
+    ChildRestore,
+    OverlaySwap,
+    MagicMount,
+    SelinuxContext,
+    ModuleSync,
+    Unmount,
+    MountVerify,
+    SelinuxRead,
+    ErofsSpawn,
+}
+
+#[derive(Debug)]
+pub enum HybridError {
+    StorageMount {
+        target: PathBuf,
+        fstype: &'static str,
+    },
+    ImageCreate(PathBuf),
+    ImageRepair(PathBuf),
+    ErofsUnsupported,
+    ErofsSpawn,
+    ErofsPack(PathBuf),
+    LowerdirTooLong {
+        target: PathBuf,
+        len: usize,
+    },
+    OverlayMount {
+        target: PathBuf,
+    },
+    ChildRestore {
+        mount_point: String,
+    },
+    OverlaySwap {
+        target: String,
+    },
+    SelinuxRead {
+        path: PathBuf,
+    },
+    SelinuxContext {
+        path: PathBuf,
+    },
+    MagicMount {
+        target: PathBuf,
+    },
+    ModuleSync {
+        module_id: String,
+    },
+    Unmount {
+        target: PathBuf,
+    },
+}
+
+impl HybridError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::StorageMount { .. } => ErrorCode::StorageMount,
+            Self::ImageCreate(_) => ErrorCode::ImageCreate,
+            Self::ImageRepair(_) => ErrorCode::ImageRepair,
+            Self::ErofsUnsupported => ErrorCode::ErofsUnsupported,
+            Self::ErofsSpawn => ErrorCode::ErofsSpawn,
+            Self::ErofsPack(_) => ErrorCode::ErofsPack,
+            Self::LowerdirTooLong { .. } => ErrorCode::LowerdirTooLong,
+            Self::OverlayMount { .. } => ErrorCode::OverlayMount,
+            Self::ChildRestore { .. } => ErrorCode::ChildRestore,
+            Self::OverlaySwap { .. } => ErrorCode::OverlaySwap,
+            Self::SelinuxRead { .. } => ErrorCode::SelinuxRead,
+            Self::SelinuxContext { .. } => ErrorCode::SelinuxContext,
+            Self::MagicMount { .. } => ErrorCode::MagicMount,
+            Self::ModuleSync { .. } => ErrorCode::ModuleSync,
+            Self::Unmount { .. } => ErrorCode::Unmount,
+        }
+    }
+}
+
+impl fmt::Display for HybridError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::StorageMount { target, fstype } => {
+                write!(
+                    f,
+                    "failed to mount {} storage at {}",
+                    fstype,
+                    target.display()
+                )
+            }
+            Self::ImageCreate(path) => write!(f, "failed to create image {}", path.display()),
+            Self::ImageRepair(path) => write!(f, "failed to repair image {}", path.display()),
+            Self::ErofsUnsupported => write!(f, "EROFS is not supported on this device"),
+            Self::ErofsSpawn => write!(f, "failed to run mkfs.erofs"),
+            Self::ErofsPack(path) => write!(f, "failed to pack EROFS image {}", path.display()),
+            Self::LowerdirTooLong { target, len } => write!(
+                f,
+                "lowerdir option for {} is too long ({} bytes)",
+                target.display(),
+                len
+            ),
+            Self::OverlayMount { target } => {
+                write!(f, "failed to mount overlayfs on {}", target.display())
+            }
+            Self::ChildRestore { mount_point } => {
+                write!(f, "failed to restore child mount {}", mount_point)
+            }
+            Self::OverlaySwap { target } => write!(f, "failed to swap overlay on {}", target),
+            Self::SelinuxRead { path } => {
+                write!(f, "failed to read SELinux context of {}", path.display())
+            }
+            Self::SelinuxContext { path } => {
+                write!(f, "failed to apply SELinux context on {}", path.display())
+            }
+            Self::MagicMount { target } => {
+                write!(f, "failed to magic mount {}", target.display())
+            }
+            Self::ModuleSync { module_id } => write!(f, "failed to sync module {}", module_id),
+            Self::Unmount { target } => write!(f, "failed to unmount {}", target.display()),
+        }
+    }
+}
+
+impl std::error::Error for HybridError {}
+
+// Finds the outermost typed error, so wrap only at the most specific failure site.
+pub fn code_of(err: &anyhow::Error) -> ErrorCode {
+    err.downcast_ref::<HybridError>()
+        .map(HybridError::code)
+        .unwrap_or_default()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorReport {
+    pub code: ErrorCode,
+    pub context: String,
+    pub message: String,
+}
+
+impl ErrorReport {
+    pub fn new(context: impl Into<String>, err: &anyhow::Error) -> Self {
+        Self {
+            code: code_of(err),
+            context: context.into(),
+            message: format!("{:#}", err),
+        }
+    }
+
+    pub fn with_default(mut self, code: ErrorCode) -> Self {
+        if self.code == ErrorCode::Unknown {
+            self.code = code;
+        }
+
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn failed_label_read_is_not_a_relabel_error() {
+        let err = crate::utils::lgetfilecon("/nonexistent/meta-hybrid").unwrap_err();
+
+        assert_eq!(code_of(&err.context("outer")), ErrorCode::SelinuxRead);
+    }
+}
@@ -4,6 +4,7 @@
 mod conf;
 mod core;
 mod defs;
+mod error;
 mod mount;
 #[cfg(any(target_os = "linux", target_os = "android"))]
 mod try_umount;
@@ -17,44 +18,13 @@ use std::path::{Path, PathBuf};
 use conf::{
     cli::{Cli, Commands},
     cli_handlers,
-    config::{CONFIG_FILE_DEFAULT, Config},
 };
-use core::{OryzaEngine, executor, granary, inventory, planner, winnow};
+use core::{OryzaEngine, granary, manifest::ExecutionManifest, state::RuntimeState};
+use error::ErrorReport;
 
 #[global_allocator]
 static GLOBAL: MiMalloc = MiMalloc;
 
-fn load_config(cli: &Cli) -> Result<Config> {
-    if let Some(config_path) = &cli.config {
-        return Config::from_file(config_path).with_context(|| {
-            format!(
-                "Failed to load config from custom path: {}",
-                config_path.display()
-            )
-        });
-    }
-
-    match Config::load_default() {
-        Ok(config) => Ok(config),
-        Err(e) => {
-            let is_not_found = e
-                .root_cause()
-                .downcast_ref::<std::io::Error>()
-                .map(|io_err| io_err.kind() == std::io::ErrorKind::NotFound)
-                .unwrap_or(false);
-
-            if is_not_found {
-                Ok(Config::default())
-            } else {
-                Err(e).context(format!(
-                    "Failed to load default config from {}",
-                    CONFIG_FILE_DEFAULT
-                ))
-            }
-        }
-    }
-}
-
 fn main() -> Result<()> {
     let cli = Cli::parse();
 
@@ -70,23 +40,30 @@ fn main() -> Result<()> {
             Commands::Modules => cli_handlers::handle_modules(&cli)?,
             Commands::Conflicts => cli_handlers::handle_conflicts(&cli)?,
             Commands::Diagnostics => cli_handlers::handle_diagnostics(&cli)?,
+            Commands::Unmount => cli_handlers::handle_unmount()?,
+            Commands::Reload => cli_handlers::handle_reload(&cli)?,
             Commands::SystemAction { action, value } => {
                 cli_handlers::handle_system_action(&cli, action, value.as_deref())?
             }
+            Commands::SwapOverlay {
+                target,
+                lowerdirs,
+                workdir,
+                upperdir,
+                disable_umount,
+            } => cli_handlers::handle_swap_overlay(
+                target,
+                lowerdirs,
+                workdir.clone(),
+                upperdir.clone(),
+                *disable_umount,
+            )?,
         }
 
         return Ok(());
     }
 
-    let mut config = load_config(&cli)?;
-
-    config.merge_with_cli(
-        cli.moduledir.clone(),
-        cli.mountsource.clone(),
-        cli.verbose,
-        cli.partitions.clone(),
-        cli.dry_run,
-    );
+    let mut config = cli_handlers::load_config(&cli)?;
 
     if !config.dry_run
         && let Err(e) = granary::engage_ratoon_protocol()
@@ -119,79 +96,7 @@ fn main() -> Result<()> {
             })
             .init();
 
-        log::info!(":: DRY-RUN / DIAGNOSTIC MODE ::");
-
-        let module_list =
-            inventory::scan(&config.moduledir, &config).context("Inventory scan failed")?;
-
-        log::info!(">> Inventory: Found {} modules", module_list.len());
-
-        let plan = planner::generate(&config, &module_list, &config.moduledir)
-            .context("Plan generation failed")?;
-
-        plan.print_visuals();
-
-        log::info!(">> Analyzing File Conflicts...");
-
-        let report = plan.analyze_conflicts();
-
-        if report.details.is_empty() {
-            log::info!("   No file conflicts detected. Clean.");
-        } else {
-            log::warn!("!! DETECTED {} FILE CONFLICTS !!", report.details.len());
-
-            let winnowed = winnow::sift_conflicts(report.details, &config.winnowing);
-
-            for c in winnowed {
-                let status = if c.is_forced { "(FORCED)" } else { "" };
-
-                log::warn!(
-                    "   [{}] {} <== {:?} >> Selected: {} {}",
-                    "CONFLICT",
-                    c.path.display(),
-                    c.contenders,
-                    c.selected,
-                    status
-                );
-            }
-        }
-
-        log::info!(">> Running System Diagnostics...");
-
-        let issues = executor::diagnose_plan(&plan);
-
-        let mut critical_count = 0;
-
-        for issue in issues {
-            match issue.level {
-                core::executor::DiagnosticLevel::Critical => {
-                    log::error!("[CRITICAL][{}] {}", issue.context, issue.message);
-
-                    critical_count += 1;
-                }
-                core::executor::DiagnosticLevel::Warning => {
-                    log::warn!("[WARN][{}] {}", issue.context, issue.message);
-                }
-                core::executor::DiagnosticLevel::Info => {
-                    log::info!("[INFO][{}] {}", issue.context, issue.message);
-                }
-            }
-        }
-
-        if critical_count > 0 {
-            log::error!(
-                ">> ❌ DIAGNOSTICS FAILED: {} critical issues found.",
-                critical_count
-            );
-
-            log::error!(">> Mounting now would likely result in a bootloop.");
-
-            std::process::exit(1);
-        } else {
-            log::info!(">> ✅ Diagnostics passed. System looks healthy.");
-        }
-
-        return Ok(());
+        return cli_handlers::handle_dry_run(&config, cli.format);
     }
 
     let _log_guard = utils::init_logging(config.verbose, Path::new(defs::DAEMON_LOG_FILE))
@@ -226,17 +131,28 @@ fn main() -> Result<()> {
         log::warn!("Granary: Failed to create boot snapshot: {}", e);
     }
 
-    OryzaEngine::new(config)
+    let boot = OryzaEngine::new(config)
         .init_storage(&mnt_base, &img_path)
-        .context("Failed to initialize storage")?
-        .scan_and_sync()
-        .context("Failed to scan and sync modules")?
-        .generate_plan()
-        .context("Failed to generate mount plan")?
-        .execute()
-        .context("Failed to execute mount plan")?
-        .finalize()
-        .context("Failed to finalize boot sequence")?;
-
-    Ok(())
+        .context("Failed to initialize storage")
+        .and_then(|e| e.scan_and_sync().context("Failed to scan and sync modules"))
+        .and_then(|e| e.generate_plan().context("Failed to generate mount plan"))
+        .and_then(|e| e.execute().context("Failed to execute mount plan"))
+        .and_then(|e| {
+            e.verify()
+                .finalize()
+                .context("Failed to finalize boot sequence")
+        });
+
+    if let Err(e) = &boot {
+        if let Err(save_err) = RuntimeState::record_failure(ErrorReport::new("boot", e)) {
+            log::error!("Failed to record boot failure: {:#}", save_err);
+        }
+
+        // Don't leave the previous boot's manifest behind.
+        if let Err(save_err) = ExecutionManifest::from_journal().save() {
+            log::error!("Failed to save mount manifest: {:#}", save_err);
+        }
+    }
+
+    boot
 }
@@ -0,0 +1,171 @@
+// Copyright 2025 Meta-Hybrid Mount Authors
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use rustix::mount::{MountFlags, UnmountFlags, mount_bind, mount_remount, unmount};
+
+use crate::{
+    mount::{
+        journal::{self, MountKind, TeardownReport},
+        magic::{self, MagicRequest},
+        overlay,
+    },
+    utils,
+};
+
+// Every mount the pipeline performs goes through here, so the executor and
+// storage setup can run against the simulator without root. Only the system
+// backend keeps the mount journal.
+pub trait MountBackend: Sync {
+    fn mount_tmpfs(&self, target: &Path, source: &str) -> Result<()>;
+
+    fn supports_overlay_xattr(&self, path: &Path) -> bool;
+
+    fn mount_image(&self, image: &Path, target: &Path) -> Result<()>;
+
+    fn repair_image(&self, image: &Path) -> Result<()>;
+
+    fn create_image(&self, image: &Path, size: u64) -> Result<()>;
+
+    // Packs `source` into a read-only EROFS image.
+    fn pack_image(&self, source: &Path, image: &Path) -> Result<()>;
+
+    fn mount_packed_image(&self, image: &Path, target: &Path) -> Result<()>;
+
+    // Gives the tree at `path` the SELinux labels of `reference`.
+    fn relabel_tree(&self, path: &Path, reference: &Path) -> Result<()>;
+
+    fn unmount(&self, target: &Path) -> Result<()>;
+
+    fn bind_readonly(&self, source: &Path, target: &Path) -> Result<()>;
+
+    fn mount_overlay(
+        &self,
+        target: &str,
+        lowerdirs: &[String],
+        workdir: Option<PathBuf>,
+        upperdir: Option<PathBuf>,
+        disable_umount: bool,
+    ) -> Result<()>;
+
+    // Checks whether the layers stack without mounting anything on the target.
+    fn probe_overlay(&self, target: &str, lowerdirs: &[String]) -> Result<()>;
+
+    fn magic_mount(&self, request: &MagicRequest) -> Result<()>;
+
+    fn temp_dir(&self) -> Result<PathBuf>;
+
+    // Marks the current end of the mount journal.
+    fn checkpoint(&self) -> usize;
+
+    // Detaches every mount made since `checkpoint`, newest first.
+    fn rollback(&self, checkpoint: usize) -> TeardownReport;
+}
+
+pub struct SystemBackend;
+
+impl MountBackend for SystemBackend {
+    // Storage, or the magic mount workspace that is unmounted again right after.
+    fn mount_tmpfs(&self, target: &Path, source: &str) -> Result<()> {
+        utils::mount_tmpfs(target, source)?;
+
+        journal::record(MountKind::Storage, target);
+
+        Ok(())
+    }
+
+    fn supports_overlay_xattr(&self, path: &Path) -> bool {
+        utils::is_overlay_xattr_supported(path)
+    }
+
+    fn mount_image(&self, image: &Path, target: &Path) -> Result<()> {
+        utils::mount_image(image, target)?;
+
+        journal::record(MountKind::Storage, target);
+
+        Ok(())
+    }
+
+    fn repair_image(&self, image: &Path) -> Result<()> {
+        utils::repair_image(image)
+    }
+
+    fn create_image(&self, image: &Path, size: u64) -> Result<()> {
+        utils::create_ext4_image(image, size)
+    }
+
+    fn pack_image(&self, source: &Path, image: &Path) -> Result<()> {
+        utils::create_erofs_image(source, image)
+    }
+
+    fn mount_packed_image(&self, image: &Path, target: &Path) -> Result<()> {
+        utils::mount_erofs_image(image, target)?;
+
+        journal::record(MountKind::Storage, target);
+
+        Ok(())
+    }
+
+    fn relabel_tree(&self, path: &Path, reference: &Path) -> Result<()> {
+        utils::relabel_tree(path, reference)
+    }
+
+    fn unmount(&self, target: &Path) -> Result<()> {
+        unmount(target, UnmountFlags::DETACH)
+            .with_context(|| format!("failed to unmount {}", target.display()))?;
+
+        journal::forget(target);
+
+        Ok(())
+    }
+
+    fn bind_readonly(&self, source: &Path, target: &Path) -> Result<()> {
+        mount_bind(source, target)
+            .with_context(|| format!("failed to bind {}", target.display()))?;
+
+        journal::record(MountKind::WinnowPin, target);
+
+        if let Err(e) = mount_remount(target, MountFlags::RDONLY | MountFlags::BIND, "") {
+            log::warn!("make file {} ro: {e:#?}", target.display());
+        }
+
+        Ok(())
+    }
+
+    fn mount_overlay(
+        &self,
+        target: &str,
+        lowerdirs: &[String],
+        workdir: Option<PathBuf>,
+        upperdir: Option<PathBuf>,
+        disable_umount: bool,
+    ) -> Result<()> {
+        overlay::mount_overlay(target, lowerdirs, workdir, upperdir, disable_umount)
+    }
+
+    fn probe_overlay(&self, target: &str, lowerdirs: &[String]) -> Result<()> {
+        overlay::probe_overlay(target, lowerdirs)
+    }
+
+    fn magic_mount(&self, request: &MagicRequest) -> Result<()> {
+        magic::mount_partitions(request)
+    }
+
+    fn temp_dir(&self) -> Result<PathBuf> {
+        let tempdir = utils::select_temp_dir()?;
+
+        utils::ensure_dir_exists(&tempdir)?;
+
+        Ok(tempdir)
+    }
+
+    fn checkpoint(&self) -> usize {
+        journal::checkpoint()
+    }
+
+    fn rollback(&self, checkpoint: usize) -> TeardownReport {
+        journal::rollback(checkpoint)
+    }
+}
@@ -0,0 +1,246 @@
+// Copyright 2025 Meta-Hybrid Mount Authors
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+};
+
+use rustix::{
+    fs::{AtFlags, CWD, StatxFlags, statx},
+    mount::{UnmountFlags, unmount},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{error::ErrorCode, utils};
+
+static JOURNAL: OnceLock<Mutex<Vec<MountRecord>>> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MountKind {
+    Storage,
+    OverlayStage,
+    OverlayRoot,
+    ChildRestore,
+    WinnowPin,
+    MagicSkeleton,
+    MagicFile,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MountRecord {
+    pub kind: MountKind,
+    pub target: PathBuf,
+    #[serde(default)]
+    pub mnt_id: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TeardownFailure {
+    pub kind: MountKind,
+    pub target: PathBuf,
+    pub code: ErrorCode,
+    pub error: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct TeardownReport {
+    pub detached: Vec<PathBuf>,
+    pub already_gone: Vec<PathBuf>,
+    pub failed: Vec<TeardownFailure>,
+}
+
+fn journal() -> &'static Mutex<Vec<MountRecord>> {
+    JOURNAL.get_or_init(|| Mutex::new(Vec::new()))
+}
+
+fn mount_id(path: &Path) -> Option<u64> {
+    let stat = statx(CWD, path, AtFlags::SYMLINK_NOFOLLOW, StatxFlags::MNT_ID).ok()?;
+
+    (stat.stx_mask & StatxFlags::MNT_ID.bits() != 0).then_some(stat.stx_mnt_id)
+}
+
+pub fn record(kind: MountKind, target: impl AsRef<Path>) {
+    let target = target.as_ref();
+
+    let entry = MountRecord {
+        kind,
+        target: target.to_path_buf(),
+        mnt_id: mount_id(target),
+    };
+
+    journal().lock().unwrap().push(entry);
+}
+
+pub fn forget(target: impl AsRef<Path>) {
+    let target = target.as_ref();
+
+    let mut records = journal().lock().unwrap();
+
+    if let Some(pos) = records.iter().rposition(|r| r.target == target) {
+        records.remove(pos);
+    }
+}
+
+// Drops a mount and every record stacked on top of it, for mounts reverted in place.
+pub fn forget_tree(root: impl AsRef<Path>) {
+    let root = root.as_ref();
+
+    journal()
+        .lock()
+        .unwrap()
+        .retain(|r| !r.target.starts_with(root));
+}
+
+pub fn snapshot() -> Vec<MountRecord> {
+    journal().lock().unwrap().clone()
+}
+
+pub fn checkpoint() -> usize {
+    journal().lock().unwrap().len()
+}
+
+// Takes down everything recorded since `checkpoint`, newest first. Mounts that
+// could not be detached stay in the journal so the manifest still lists them.
+pub fn rollback(checkpoint: usize) -> TeardownReport {
+    let records = {
+        let mut journal = journal().lock().unwrap();
+
+        let at = checkpoint.min(journal.len());
+
+        journal.split_off(at)
+    };
+
+    let report = unwind(&records);
+
+    let failed: Vec<&Path> = report.failed.iter().map(|f| f.target.as_path()).collect();
+
+    journal().lock().unwrap().extend(
+        records
+            .iter()
+            .filter(|r| failed.contains(&r.target.as_path()))
+            .cloned(),
+    );
+
+    report
+}
+
+// The mount id pins a record to the exact mount we created, so a stock mount
+// that became visible again is never taken down by mistake.
+pub fn is_live(record: &MountRecord) -> bool {
+    match record.mnt_id {
+        Some(id) => mount_id(&record.target) == Some(id),
+        None => utils::is_mounted(&record.target),
+    }
+}
+
+// This is how SystemBackend rolls back, so it detaches directly rather than
+// through a backend; the simulator keeps its own mount list instead.
+pub fn unwind(records: &[MountRecord]) -> TeardownReport {
+    let mut report = TeardownReport::default();
+
+    for record in records.iter().rev() {
+        if !is_live(record) {
+            log::debug!(
+                "Skip {:?} {}: no longer mounted by us",
+                record.kind,
+                record.target.display()
+            );
+
+            report.already_gone.push(record.target.clone());
+
+            continue;
+        }
+
+        match unmount(&record.target, UnmountFlags::DETACH) {
+            Ok(_) => {
+                log::info!("Detached {:?} {}", record.kind, record.target.display());
+
+                report.detached.push(record.target.clone());
+            }
+            Err(e) => {
+                log::warn!(
+                    "Failed to detach {:?} {}: {}",
+                    record.kind,
+                    record.target.display(),
+                    e
+                );
+
+                report.failed.push(TeardownFailure {
+                    kind: record.kind,
+                    target: record.target.clone(),
+                    code: ErrorCode::Unmount,
+                    error: e.to_string(),
+                });
+            }
+        }
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use super::*;
+
+    fn record_at(kind: MountKind, target: &Path) -> MountRecord {
+        MountRecord {
+            kind,
+            target: target.to_path_buf(),
+            mnt_id: None,
+        }
+    }
+
+    #[test]
+    fn unwind_walks_records_newest_first() {
+        let root = env::temp_dir().join(format!("meta-hybrid-journal-{}", std::process::id()));
+
+        let targets: Vec<PathBuf> = ["storage", "overlay", "pin"]
+            .iter()
+            .map(|name| root.join(name))
+            .collect();
+
+        for target in &targets {
+            fs::create_dir_all(target).unwrap();
+        }
+
+        let records = [
+            record_at(MountKind::Storage, &targets[0]),
+            record_at(MountKind::OverlayRoot, &targets[1]),
+            record_at(MountKind::WinnowPin, &targets[2]),
+        ];
+
+        let report = unwind(&records);
+
+        let _ = fs::remove_dir_all(&root);
+
+        // Nothing is mounted there, so every record is skipped, in reverse.
+        assert!(report.detached.is_empty() && report.failed.is_empty());
+
+        assert_eq!(
+            report.already_gone,
+            [targets[2].clone(), targets[1].clone(), targets[0].clone()]
+        );
+    }
+
+    #[test]
+    fn is_live_only_matches_the_recorded_mount() {
+        let root = Path::new("/");
+
+        let id = mount_id(root).expect("statx does not report mount ids");
+
+        let mut record = record_at(MountKind::Storage, root);
+
+        record.mnt_id = Some(id);
+
+        assert!(is_live(&record));
+
+        // Another mount on the same path must not be taken for ours.
+        record.mnt_id = Some(id + 1);
+
+        assert!(!is_live(&record));
+    }
+}
@@ -20,9 +20,14 @@ use rustix::{
 };
 
 use crate::{
+    core::{contexts::FileContexts, inventory::ModuleRules},
     defs::{DISABLE_FILE_NAME, REMOVE_FILE_NAME, SKIP_MOUNT_FILE_NAME},
-    mount::node::{Node, NodeFileType},
-    utils::{ensure_dir_exists, lgetfilecon, lsetfilecon},
+    error::HybridError,
+    mount::{
+        journal::{self, MountKind},
+        node::{Node, NodeFileType},
+    },
+    utils::{ensure_dir_exists, lgetfilecon, lsetfilecon_lossy},
 };
 
 #[cfg(any(target_os = "linux", target_os = "android"))]
@@ -30,15 +35,22 @@ use crate::try_umount::send_unmountable;
 
 const ROOT_PARTITIONS: [&str; 4] = ["vendor", "system_ext", "product", "odm"];
 
-fn merge_nodes(high: &mut Node, low: Node) {
+fn merge_nodes(high: &mut Node, mut low: Node) {
     if high.module_path.is_none() {
         high.module_path = low.module_path;
 
         high.file_type = low.file_type;
 
         high.replace = low.replace;
+
+        // The lower node now decides what is served, so it leads the list.
+        if high.module_path.is_some() {
+            std::mem::swap(&mut high.contributors, &mut low.contributors);
+        }
     }
 
+    high.contributors.append(&mut low.contributors);
+
     for (name, low_child) in low.children {
         match high.children.entry(name) {
             Entry::Vacant(v) => {
@@ -53,8 +65,10 @@ fn merge_nodes(high: &mut Node, low: Node) {
 
 fn process_module(
     path: &Path,
+    sysroot: &Path,
     extra_partitions: &[String],
     exclusion_list: Option<&HashSet<String>>,
+    rules: Option<&ModuleRules>,
 ) -> Result<(Node, Node)> {
     let mut root = Node::new_root("");
 
@@ -79,7 +93,7 @@ fn process_module(
         let mod_system = path.join("system");
 
         if mod_system.is_dir() {
-            system.collect_module_files(&mod_system)?;
+            system.collect_module_files(&mod_system, rules.map(|r| (r, "system")))?;
         }
     }
 
@@ -100,9 +114,11 @@ fn process_module(
                 node.file_type = NodeFileType::Directory;
 
                 node.module_path = None;
+
+                node.contributors.clear();
             }
 
-            node.collect_module_files(&mod_part)?;
+            node.collect_module_files(&mod_part, rules.map(|r| (r, partition)))?;
         }
     }
 
@@ -115,9 +131,9 @@ fn process_module(
             continue;
         }
 
-        let path_of_root = Path::new("/").join(partition);
+        let path_of_root = sysroot.join(partition);
 
-        let path_of_system = Path::new("/system").join(partition);
+        let path_of_system = sysroot.join("system").join(partition);
 
         if path_of_root.is_dir() && path_of_system.is_symlink() {
             let name = partition.clone();
@@ -130,7 +146,7 @@ fn process_module(
                     .entry(name)
                     .or_insert_with(|| Node::new_root(partition));
 
-                node.collect_module_files(&mod_part)?;
+                node.collect_module_files(&mod_part, rules.map(|r| (r, partition.as_str())))?;
             }
         } else if path_of_root.is_dir() {
             let name = partition.clone();
@@ -143,7 +159,7 @@ fn process_module(
                     .entry(name)
                     .or_insert_with(|| Node::new_root(partition));
 
-                node.collect_module_files(&mod_part)?;
+                node.collect_module_files(&mod_part, rules.map(|r| (r, partition.as_str())))?;
             }
         }
     }
@@ -153,15 +169,23 @@ fn process_module(
 
 fn collect_module_files(
     module_paths: &[PathBuf],
+    sysroot: &Path,
     extra_partitions: &[String],
     exclusions: &HashMap<PathBuf, HashSet<String>>,
+    module_rules: &HashMap<PathBuf, ModuleRules>,
 ) -> Result<Option<Node>> {
     let (mut final_root, mut final_system) = module_paths
         .par_iter()
         .map(|path| {
             let exclusion = exclusions.get(path);
 
-            process_module(path, extra_partitions, exclusion)
+            process_module(
+                path,
+                sysroot,
+                extra_partitions,
+                exclusion,
+                module_rules.get(path),
+            )
         })
         .reduce(
             || Ok((Node::new_root(""), Node::new_root("system"))),
@@ -189,9 +213,9 @@ fn collect_module_files(
         ];
 
         for (partition, require_symlink) in BUILTIN_CHECKS {
-            let path_of_root = Path::new("/").join(partition);
+            let path_of_root = sysroot.join(partition);
 
-            let path_of_system = Path::new("/system").join(partition);
+            let path_of_system = sysroot.join("system").join(partition);
 
             if path_of_root.is_dir() && (!require_symlink || path_of_system.is_symlink()) {
                 let name = partition.to_string();
@@ -206,12 +230,48 @@ fn collect_module_files(
             .children
             .insert("system".to_string(), final_system);
 
+        let default_rules = ModuleRules::default();
+
+        let overrides: Vec<(&PathBuf, FileContexts)> = module_paths
+            .iter()
+            .map(|path| {
+                let rules = module_rules.get(path).unwrap_or(&default_rules);
+
+                (path, FileContexts::for_module(path, rules))
+            })
+            .filter(|(_, contexts)| !contexts.is_empty())
+            .collect();
+
+        if !overrides.is_empty() {
+            apply_context_overrides(&mut final_root, Path::new("/"), &overrides);
+        }
+
         Ok(Some(final_root))
     } else {
         Ok(None)
     }
 }
 
+// Runs once partitions are relocated, so lookups see the paths used on device.
+fn apply_context_overrides(node: &mut Node, path: &Path, overrides: &[(&PathBuf, FileContexts)]) {
+    for child in node.children.values_mut() {
+        let child_path = path.join(&child.name);
+
+        if let Some(module_path) = &child.module_path
+            && let Some((_, contexts)) = overrides
+                .iter()
+                .find(|(root, _)| module_path.starts_with(root))
+            && let Ok(metadata) = module_path.symlink_metadata()
+        {
+            child.context = contexts
+                .lookup(&child_path, metadata.file_type())
+                .map(str::to_string);
+        }
+
+        apply_context_overrides(child, &child_path, overrides);
+    }
+}
+
 fn clone_symlink<S>(src: S, dst: S) -> Result<()>
 where
     S: AsRef<Path>,
@@ -220,7 +280,7 @@ where
 
     symlink(&src_symlink, dst.as_ref())?;
 
-    lsetfilecon(dst.as_ref(), lgetfilecon(src.as_ref())?.as_str())?;
+    lsetfilecon_lossy(dst.as_ref(), lgetfilecon(src.as_ref())?.as_str());
 
     Ok(())
 }
@@ -252,7 +312,7 @@ where
             Some(Gid::from_raw(metadata.gid())),
         )?;
 
-        lsetfilecon(&work_dir_path, lgetfilecon(&path)?.as_str())?;
+        lsetfilecon_lossy(&work_dir_path, lgetfilecon(&path)?.as_str());
 
         for entry in read_dir(&path)?.flatten() {
             mount_mirror(&path, &work_dir_path, &entry)?;
@@ -362,23 +422,35 @@ impl MagicMount {
                 self.work_dir_path.display()
             );
 
-            mount_bind(module_path, target_path).with_context(|| {
-                #[cfg(any(target_os = "linux", target_os = "android"))]
-                if self.umount {
-                    let _ = send_unmountable(target_path);
-                }
+            if let Some(context) = &self.node.context {
+                lsetfilecon_lossy(module_path, context);
+            }
 
-                format!(
-                    "mount module file {} -> {}",
-                    module_path.display(),
-                    self.work_dir_path.display(),
-                )
-            })?;
+            mount_bind(module_path, target_path)
+                .context(HybridError::MagicMount {
+                    target: self.path.clone(),
+                })
+                .with_context(|| {
+                    #[cfg(any(target_os = "linux", target_os = "android"))]
+                    if self.umount {
+                        let _ = send_unmountable(target_path);
+                    }
+
+                    format!(
+                        "mount module file {} -> {}",
+                        module_path.display(),
+                        self.work_dir_path.display(),
+                    )
+                })?;
 
             if let Err(e) = mount_remount(target_path, MountFlags::RDONLY | MountFlags::BIND, "") {
                 log::warn!("make file {} ro: {e:#?}", target_path.display());
             }
 
+            if !self.has_tmpfs {
+                journal::record(MountKind::MagicFile, target_path);
+            }
+
             Ok(())
         } else {
             bail!("cannot mount root file {}!", self.path.display());
@@ -424,7 +496,12 @@ impl MagicMount {
                 Some(Gid::from_raw(metadata.gid())),
             )?;
 
-            lsetfilecon(&self.work_dir_path, lgetfilecon(path)?.as_str())?;
+            let context = match &self.node.context {
+                Some(context) => context.clone(),
+                None => lgetfilecon(path)?,
+            };
+
+            lsetfilecon_lossy(&self.work_dir_path, &context);
         }
 
         if create_tmpfs {
@@ -435,7 +512,9 @@ impl MagicMount {
             );
 
             mount_bind(&self.work_dir_path, &self.work_dir_path)
-                .context("bind self")
+                .context(HybridError::MagicMount {
+                    target: self.path.clone(),
+                })
                 .with_context(|| {
                     format!(
                         "creating tmpfs for {} at {}",
@@ -534,7 +613,9 @@ impl MagicMount {
             }
 
             mount_move(&self.work_dir_path, &self.path)
-                .context("move self")
+                .context(HybridError::MagicMount {
+                    target: self.path.clone(),
+                })
                 .with_context(|| {
                     format!(
                         "moving tmpfs {} -> {}",
@@ -547,6 +628,8 @@ impl MagicMount {
                 log::warn!("make dir {} private: {e:#?}", self.path.display());
             }
 
+            journal::record(MountKind::MagicSkeleton, &self.path);
+
             #[cfg(any(target_os = "linux", target_os = "android"))]
             if self.umount {
                 let _ = send_unmountable(&self.path);
@@ -572,6 +655,10 @@ impl MagicMount {
                 )
             })?;
 
+            if let Some(context) = &self.node.context {
+                lsetfilecon_lossy(&self.work_dir_path, context);
+            }
+
             Ok(())
         } else {
             bail!("cannot mount root symlink {}!", self.path.display());
@@ -579,16 +666,29 @@ impl MagicMount {
     }
 }
 
-pub fn mount_partitions(
-    tmp_path: &Path,
-    module_paths: &[PathBuf],
-    mount_source: &str,
-    extra_partitions: &[String],
-    exclusions: HashMap<PathBuf, HashSet<String>>,
-    #[cfg(any(target_os = "linux", target_os = "android"))] disable_umount: bool,
-    #[cfg(not(any(target_os = "linux", target_os = "android")))] _disable_umount: bool,
-) -> Result<()> {
-    if let Some(root) = collect_module_files(module_paths, extra_partitions, &exclusions)? {
+pub struct MagicRequest<'a> {
+    pub tmp_path: &'a Path,
+    pub module_paths: &'a [PathBuf],
+    pub sysroot: &'a Path,
+    pub mount_source: &'a str,
+    pub extra_partitions: &'a [String],
+    pub exclusions: HashMap<PathBuf, HashSet<String>>,
+    pub module_rules: &'a HashMap<PathBuf, ModuleRules>,
+    pub disable_umount: bool,
+}
+
+pub fn build_tree(request: &MagicRequest) -> Result<Option<Node>> {
+    collect_module_files(
+        request.module_paths,
+        request.sysroot,
+        request.extra_partitions,
+        &request.exclusions,
+        request.module_rules,
+    )
+}
+
+pub fn mount_partitions(request: &MagicRequest) -> Result<()> {
+    if let Some(root) = build_tree(request)? {
         log::debug!("[Magic Mount Tree Constructed]");
 
         let tree_str = format!("{:?}", root);
@@ -597,29 +697,31 @@ pub fn mount_partitions(
             log::debug!("   {}", line);
         }
 
-        let tmp_dir = tmp_path.join("workdir");
+        let tmp_dir = request.tmp_path.join("workdir");
 
         ensure_dir_exists(&tmp_dir)?;
 
         mount(
-            mount_source,
+            request.mount_source,
             &tmp_dir,
             "tmpfs",
             MountFlags::empty(),
             None::<&std::ffi::CStr>,
         )
-        .context("mount tmp")?;
+        .context(HybridError::MagicMount {
+            target: tmp_dir.clone(),
+        })?;
 
         mount_change(&tmp_dir, MountPropagationFlags::PRIVATE).context("make tmp private")?;
 
         let result = {
             MagicMount::new(
                 &root,
-                Path::new("/"),
+                request.sysroot,
                 tmp_dir.as_path(),
                 false,
                 #[cfg(any(target_os = "linux", target_os = "android"))]
-                !disable_umount,
+                !request.disable_umount,
             )
             .do_magic_mount()
         };
@@ -1,6 +1,10 @@
 // Copyright 2025 Meta-Hybrid Mount Authors
 // SPDX-License-Identifier: GPL-3.0-or-later
 
+pub mod backend;
+pub mod journal;
 pub mod magic;
 pub mod node;
 pub mod overlay;
+#[cfg(test)]
+pub mod sim;
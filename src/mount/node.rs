@@ -2,13 +2,16 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
 use std::{
-    collections::HashMap,
+    collections::{BTreeSet, HashMap},
     fmt,
     fs::FileType,
-    path::{Component, PathBuf},
+    path::{Component, Path, PathBuf},
 };
 
-use crate::core::modules::ModuleFile;
+use crate::core::{
+    inventory::{ModuleRules, MountMode},
+    modules::ModuleFile,
+};
 
 #[derive(PartialEq, Eq, Hash, Clone, Debug, Copy)]
 pub enum NodeFileType {
@@ -47,6 +50,10 @@ pub struct Node {
     pub file_type: NodeFileType,
     pub children: HashMap<String, Self>,
     pub module_path: Option<PathBuf>,
+    // Every module file that provides this path, highest precedence first.
+    pub contributors: Vec<PathBuf>,
+    // Label a module asked for through its context overrides.
+    pub context: Option<String>,
     pub replace: bool,
     pub skip: bool,
 }
@@ -91,7 +98,10 @@ impl fmt::Debug for Node {
             };
 
             let source_str = if let Some(p) = &node.module_path {
-                format!(" -> {}", p.display())
+                match node.contributors.len() {
+                    0 | 1 => format!(" -> {}", p.display()),
+                    n => format!(" -> {} (+{} shadowed)", p.display(), n - 1),
+                }
             } else {
                 String::new()
             };
@@ -138,23 +148,73 @@ impl Node {
             name: name.into(),
             file_type: NodeFileType::Directory,
             module_path: None,
+            contributors: Vec::new(),
+            context: None,
             children: HashMap::new(),
             replace: false,
             skip: false,
         }
     }
 
-    pub fn collect_module_files(&mut self, root: &PathBuf) -> anyhow::Result<()> {
+    pub fn collect_module_files(
+        &mut self,
+        root: &PathBuf,
+        rules: Option<(&ModuleRules, &str)>,
+    ) -> anyhow::Result<()> {
+        let rule_path = |relative: &Path| -> Option<(&ModuleRules, String)> {
+            rules.map(|(rules, prefix)| {
+                (
+                    rules,
+                    Path::new(prefix)
+                        .join(relative)
+                        .to_string_lossy()
+                        .to_string(),
+                )
+            })
+        };
+
+        let traversable = |entry: &walkdir::DirEntry| -> bool {
+            let Ok(relative) = entry.path().strip_prefix(root) else {
+                return true;
+            };
+
+            match rule_path(relative) {
+                Some((rules, path)) => {
+                    rules.selects(&path, entry.file_type().is_dir(), &MountMode::Magic)
+                }
+                None => true,
+            }
+        };
+
+        let mut selected = BTreeSet::new();
+
         for entry in walkdir::WalkDir::new(root)
             .min_depth(1)
             .into_iter()
+            .filter_entry(traversable)
             .filter_map(|e| e.ok())
         {
-            let path = entry.path();
+            let relative_path = entry.path().strip_prefix(root)?.to_path_buf();
 
-            let relative_path = path.strip_prefix(root)?;
+            if let Some((rules, path)) = rule_path(&relative_path)
+                && rules.get_mode(&path).mode != MountMode::Magic
+            {
+                continue;
+            }
+
+            // Directories only walked through to reach magic entries still need
+            // their module path, or new directories could not be created.
+            for ancestor in relative_path.ancestors().skip(1) {
+                if ancestor.as_os_str().is_empty() || !selected.insert(ancestor.to_path_buf()) {
+                    break;
+                }
+            }
+
+            selected.insert(relative_path);
+        }
 
-            let module_file = ModuleFile::new(root, relative_path)?;
+        for relative_path in selected {
+            let module_file = ModuleFile::new(root, &relative_path)?;
 
             if module_file.is_replace_file {
                 continue;
@@ -190,11 +250,15 @@ impl Node {
                         name: name.clone(),
                         file_type,
                         module_path: None,
+                        contributors: Vec::new(),
+                        context: None,
                         children: HashMap::new(),
                         replace: false,
                         skip: false,
                     });
 
+                node.contributors.push(module_file.real_path.clone());
+
                 if !module_file.is_whiteout {
                     node.module_path = Some(module_file.real_path.clone());
 
@@ -214,6 +278,8 @@ impl Node {
                         name,
                         file_type: NodeFileType::Directory,
                         module_path: None,
+                        contributors: Vec::new(),
+                        context: None,
                         children: HashMap::new(),
                         replace: false,
                         skip: false,
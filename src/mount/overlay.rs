@@ -1,14 +1,16 @@
 // Copyright 2025 Meta-Hybrid Mount Authors
 // SPDX-License-Identifier: GPL-3.0-or-later
 
-use anyhow::{Context, Result, bail};
+use anyhow::{Context, Result};
 use log::{info, warn};
+use serde::{Deserialize, Serialize};
 use std::{
     ffi::CString,
     fs,
     io::{BufRead, BufReader},
     os::fd::{AsRawFd, OwnedFd},
     path::{Path, PathBuf},
+    process::Command,
     time::{SystemTime, UNIX_EPOCH},
 };
 
@@ -18,9 +20,13 @@ use rustix::{
     mount::*,
 };
 
-use crate::defs::{KSU_OVERLAY_SOURCE, RUN_DIR};
 #[cfg(any(target_os = "linux", target_os = "android"))]
 use crate::try_umount::send_unmountable;
+use crate::{
+    defs::{KSU_OVERLAY_SOURCE, RUN_DIR},
+    error::HybridError,
+    mount::journal::{self, MountKind},
+};
 
 const PAGE_LIMIT: usize = 4000;
 
@@ -49,15 +55,30 @@ impl Drop for StagedMountGuard {
 }
 
 fn umount_dir(src: impl AsRef<Path>) -> Result<()> {
-    unmount(src.as_ref(), UnmountFlags::DETACH)
-        .with_context(|| format!("Failed to umount {}", src.as_ref().display()))?;
+    unmount(src.as_ref(), UnmountFlags::DETACH).context(HybridError::Unmount {
+        target: src.as_ref().to_path_buf(),
+    })?;
 
     Ok(())
 }
 
+// Overlayfs refuses redirect_dir and metacopy without trusted.* xattrs, which
+// the user namespace of the test sandbox cannot set.
+#[cfg(test)]
+fn in_user_namespace() -> bool {
+    fs::read_to_string("/proc/self/uid_map")
+        .map(|map| !map.split_whitespace().eq(["0", "0", "4294967295"]))
+        .unwrap_or(false)
+}
+
 fn get_overlay_features() -> String {
     let mut features = String::new();
 
+    #[cfg(test)]
+    if in_user_namespace() {
+        return features;
+    }
+
     if Path::new("/sys/module/overlay/parameters/redirect_dir").exists() {
         features.push_str(",redirect_dir=on");
     }
@@ -73,7 +94,7 @@ fn get_overlay_features() -> String {
     features
 }
 
-fn get_sub_mounts(parent: &str) -> Result<Vec<String>> {
+pub fn get_sub_mounts(parent: &str) -> Result<Vec<String>> {
     let file = fs::File::open("/proc/mounts").context("Failed to open /proc/mounts")?;
 
     let reader = BufReader::new(file);
@@ -308,9 +329,26 @@ fn mount_overlayfs_staged(
 
     guard.committed = true;
 
+    for stage in &guard.mounts {
+        journal::record(MountKind::OverlayStage, stage);
+    }
+
     Ok(())
 }
 
+fn overlay_failure(lowerdir_config: &str, dest: &Path) -> HybridError {
+    if lowerdir_config.len() >= PAGE_LIMIT {
+        HybridError::LowerdirTooLong {
+            target: dest.to_path_buf(),
+            len: lowerdir_config.len(),
+        }
+    } else {
+        HybridError::OverlayMount {
+            target: dest.to_path_buf(),
+        }
+    }
+}
+
 fn do_mount_overlay(
     lowerdir_config: &str,
     upperdir: Option<PathBuf>,
@@ -382,7 +420,8 @@ fn do_mount_overlay(
             MountFlags::empty(),
             Some(data_c.as_c_str()),
         )
-        .with_context(|| format!("Legacy mount failed (fsopen also failed: {})", fsopen_err))?;
+        .with_context(|| format!("Legacy mount failed (fsopen also failed: {})", fsopen_err))
+        .with_context(|| overlay_failure(lowerdir_config, dest.as_ref()))?;
     }
 
     #[cfg(any(target_os = "linux", target_os = "android"))]
@@ -429,6 +468,8 @@ fn mount_overlay_child(
             }
         }
 
+        journal::record(MountKind::ChildRestore, mount_point);
+
         #[cfg(any(target_os = "linux", target_os = "android"))]
         if !disable_umount {
             let _ = send_unmountable(mount_point);
@@ -492,12 +533,18 @@ fn mount_overlay_child(
             }
         }
 
+        journal::record(MountKind::ChildRestore, mount_point);
+
         #[cfg(any(target_os = "linux", target_os = "android"))]
         if !disable_umount {
             let _ = send_unmountable(mount_point);
         }
-    } else if let StashedMount::Legacy(path) = stock {
-        let _ = fs::remove_dir(path);
+    } else {
+        journal::record(MountKind::ChildRestore, mount_point);
+
+        if let StashedMount::Legacy(path) = stock {
+            let _ = fs::remove_dir(path);
+        }
     }
 
     Ok(())
@@ -604,6 +651,8 @@ pub fn mount_overlay(
     )
     .with_context(|| format!("mount overlayfs for root {target_root} failed"))?;
 
+    journal::record(MountKind::OverlayRoot, target_root);
+
     for (mount_point, relative, stock) in stashed_mounts {
         if let Err(e) = mount_overlay_child(
             &mount_point,
@@ -624,11 +673,218 @@ pub fn mount_overlay(
                     target_root,
                     umount_err
                 );
+            } else {
+                journal::forget_tree(target_root);
             }
 
-            bail!("Child mount restoration failed: {}", e);
+            return Err(e.context(HybridError::ChildRestore { mount_point }));
         }
     }
 
     Ok(())
 }
+
+// Stacks the layers on a scratch directory and drops the mount right away, to
+// tell whether they can be combined at all. Child mounts and the rw layers are
+// left out, so only failures caused by the layers themselves show up.
+pub fn probe_overlay(target_root: &str, module_roots: &[String]) -> Result<()> {
+    let probe_dir = Path::new(RUN_DIR)
+        .join("probe")
+        .join(target_root.trim_start_matches('/').replace('/', "_"));
+
+    fs::create_dir_all(&probe_dir)
+        .with_context(|| format!("failed to create probe dir {}", probe_dir.display()))?;
+
+    let lowerdir_config = module_roots
+        .iter()
+        .map(String::as_str)
+        .chain(std::iter::once(target_root))
+        .collect::<Vec<_>>()
+        .join(":");
+
+    let result = do_mount_overlay(
+        &lowerdir_config,
+        None,
+        None,
+        &probe_dir,
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        true,
+    );
+
+    if result.is_ok() {
+        let _ = umount_dir(&probe_dir);
+    }
+
+    let _ = fs::remove_dir(&probe_dir);
+
+    result
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SwapOutcome {
+    Replaced,
+    Stacked,
+}
+
+// What the swap helper prints for its parent: the outcome and the mounts it
+// left in the host namespace, oldest first.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SwapReply {
+    pub outcome: SwapOutcome,
+    pub mounts: Vec<(MountKind, PathBuf)>,
+}
+
+// Builds the new overlay in a private namespace where the old one is already
+// gone, so the stock root can be used as the lowest layer, then hands the
+// finished tree back to the host namespace in a single move_mount.
+// setns needs a single-threaded caller, so this only runs in the helper process
+// `swap_overlay` spawns.
+pub fn swap_in_private_ns(
+    target_root: &str,
+    module_roots: &[String],
+    workdir: Option<PathBuf>,
+    upperdir: Option<PathBuf>,
+    #[cfg(any(target_os = "linux", target_os = "android"))] disable_umount: bool,
+) -> Result<SwapReply> {
+    let host_ns = fs::File::open("/proc/self/ns/mnt").context("failed to open host mount ns")?;
+
+    if unsafe { libc::unshare(libc::CLONE_NEWNS) } != 0 {
+        return Err(std::io::Error::last_os_error()).context("failed to unshare mount ns");
+    }
+
+    mount_change(
+        "/",
+        MountPropagationFlags::PRIVATE | MountPropagationFlags::REC,
+    )
+    .context("failed to make private mount ns")?;
+
+    umount_dir(target_root)?;
+
+    mount_overlay(
+        target_root,
+        module_roots,
+        workdir,
+        upperdir,
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        disable_umount,
+    )?;
+
+    let tree = open_tree(
+        CWD,
+        target_root,
+        OpenTreeFlags::OPEN_TREE_CLOEXEC
+            | OpenTreeFlags::OPEN_TREE_CLONE
+            | OpenTreeFlags::AT_RECURSIVE,
+    )
+    .with_context(|| format!("open_tree failed for {}", target_root))?;
+
+    if unsafe { libc::setns(host_ns.as_raw_fd(), libc::CLONE_NEWNS) } != 0 {
+        return Err(std::io::Error::last_os_error()).context("failed to return to host mount ns");
+    }
+
+    let outcome = match move_mount(
+        tree.as_fd(),
+        "",
+        CWD,
+        target_root,
+        MoveMountFlags::MOVE_MOUNT_F_EMPTY_PATH | MoveMountFlags::MOVE_MOUNT_BENEATH,
+    ) {
+        Ok(_) => {
+            umount_dir(target_root).context("failed to detach previous overlay")?;
+
+            SwapOutcome::Replaced
+        }
+        Err(e) => {
+            info!(
+                "move_mount beneath {} unavailable ({}), stacking on top",
+                target_root, e
+            );
+
+            move_mount(
+                tree.as_fd(),
+                "",
+                CWD,
+                target_root,
+                MoveMountFlags::MOVE_MOUNT_F_EMPTY_PATH,
+            )
+            .with_context(|| format!("move_mount failed to {}", target_root))?;
+
+            SwapOutcome::Stacked
+        }
+    };
+
+    // Staging mounts stayed behind in the private namespace; only the moved
+    // tree is visible to the host.
+    let mounts = journal::snapshot()
+        .into_iter()
+        .filter(|r| r.kind != MountKind::OverlayStage && r.target.starts_with(target_root))
+        .map(|r| (r.kind, r.target))
+        .collect();
+
+    Ok(SwapReply { outcome, mounts })
+}
+
+// Replaces a live overlay without a window where the stock partition shows through.
+// The work happens in a fresh copy of this binary, since our own process already
+// runs threads. The mounts it reports are journaled here, where mount ids are
+// resolved in the host namespace.
+pub fn swap_overlay(
+    target_root: &str,
+    module_roots: &[String],
+    workdir: Option<PathBuf>,
+    upperdir: Option<PathBuf>,
+    disable_umount: bool,
+) -> Result<SwapOutcome> {
+    let swap_failure = || HybridError::OverlaySwap {
+        target: target_root.to_string(),
+    };
+
+    let exe = std::env::current_exe().context("failed to locate own executable")?;
+
+    let mut cmd = Command::new(exe);
+
+    cmd.args(["swap-overlay", "--target", target_root]);
+
+    for root in module_roots {
+        cmd.arg("--lowerdir").arg(root);
+    }
+
+    if let Some(workdir) = &workdir {
+        cmd.arg("--workdir").arg(workdir);
+    }
+
+    if let Some(upperdir) = &upperdir {
+        cmd.arg("--upperdir").arg(upperdir);
+    }
+
+    if disable_umount {
+        cmd.arg("--disable-umount");
+    }
+
+    let output = cmd
+        .output()
+        .context("failed to spawn swap helper")
+        .with_context(swap_failure)?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+
+        let detail = match stderr.trim() {
+            "" => format!("swap helper exited with {}", output.status),
+            message => message.to_string(),
+        };
+
+        return Err(anyhow::anyhow!(detail).context(swap_failure()));
+    }
+
+    let reply: SwapReply = serde_json::from_slice(&output.stdout)
+        .context("malformed swap helper reply")
+        .with_context(swap_failure)?;
+
+    for (kind, target) in &reply.mounts {
+        journal::record(*kind, target);
+    }
+
+    Ok(reply.outcome)
+}
@@ -0,0 +1,279 @@
+// Copyright 2025 Meta-Hybrid Mount Authors
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::{Result, bail};
+use walkdir::WalkDir;
+
+use crate::{
+    error::HybridError,
+    mount::{
+        backend::MountBackend,
+        journal::TeardownReport,
+        magic::{self, MagicRequest},
+        node::{Node, NodeFileType},
+    },
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SimFs {
+    Tmpfs,
+    Image,
+    Bind,
+    Overlay,
+    Magic,
+}
+
+#[derive(Debug, Clone)]
+pub struct SimMount {
+    pub fs: SimFs,
+    pub target: PathBuf,
+    pub sources: Vec<PathBuf>,
+}
+
+#[derive(Default)]
+struct SimState {
+    mounts: Vec<SimMount>,
+    // Visible path -> backing file for everything mounted over the stock tree.
+    views: BTreeMap<PathBuf, PathBuf>,
+    failing: HashSet<PathBuf>,
+}
+
+// Records mounts instead of performing them. Module trees are still read from
+// disk, so plans built from real fixtures can be replayed without privileges.
+pub struct SimBackend {
+    state: Mutex<SimState>,
+    temp_root: PathBuf,
+}
+
+impl SimBackend {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(SimState::default()),
+            temp_root: PathBuf::from("/sim/tmp"),
+        }
+    }
+
+    pub fn fail_at(&self, target: impl AsRef<Path>) {
+        self.state
+            .lock()
+            .unwrap()
+            .failing
+            .insert(target.as_ref().to_path_buf());
+    }
+
+    pub fn mount_sources(&self, fs: SimFs, target: impl AsRef<Path>) -> Option<Vec<PathBuf>> {
+        self.state
+            .lock()
+            .unwrap()
+            .mounts
+            .iter()
+            .rfind(|m| m.fs == fs && m.target == target.as_ref())
+            .map(|m| m.sources.clone())
+    }
+
+    pub fn source_of(&self, path: impl AsRef<Path>) -> Option<PathBuf> {
+        self.state.lock().unwrap().views.get(path.as_ref()).cloned()
+    }
+
+    // Targets and layers can both be marked as failing, so a bad layer breaks
+    // every overlay it is part of.
+    fn check_layers(&self, target: &Path, lowerdirs: &[String]) -> Result<()> {
+        let state = self.state.lock().unwrap();
+
+        if let Some(bad) = lowerdirs
+            .iter()
+            .map(Path::new)
+            .chain(std::iter::once(target))
+            .find(|path| state.failing.contains(*path))
+        {
+            bail!("simulated overlay failure at {}", bad.display());
+        }
+
+        Ok(())
+    }
+
+    fn attach(&self, fs: SimFs, target: &Path, sources: Vec<PathBuf>) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+
+        if state.failing.contains(target) {
+            bail!("simulated {:?} failure at {}", fs, target.display());
+        }
+
+        state.mounts.push(SimMount {
+            fs,
+            target: target.to_path_buf(),
+            sources,
+        });
+
+        Ok(())
+    }
+
+    fn show(&self, path: PathBuf, source: PathBuf) {
+        self.state.lock().unwrap().views.insert(path, source);
+    }
+
+    fn hide(&self, path: &Path) {
+        self.state.lock().unwrap().views.remove(path);
+    }
+
+    fn project(&self, node: &Node, path: &Path) {
+        if node.skip {
+            return;
+        }
+
+        match (node.file_type, &node.module_path) {
+            (NodeFileType::Whiteout, _) => self.hide(path),
+            (NodeFileType::Directory, _) => {
+                for child in node.children.values() {
+                    self.project(child, &path.join(&child.name));
+                }
+            }
+            (_, Some(source)) => self.show(path.to_path_buf(), source.clone()),
+            _ => {}
+        }
+    }
+}
+
+impl MountBackend for SimBackend {
+    fn mount_tmpfs(&self, target: &Path, _source: &str) -> Result<()> {
+        self.attach(SimFs::Tmpfs, target, Vec::new())
+    }
+
+    fn supports_overlay_xattr(&self, _path: &Path) -> bool {
+        true
+    }
+
+    fn mount_image(&self, image: &Path, target: &Path) -> Result<()> {
+        self.attach(SimFs::Image, target, vec![image.to_path_buf()])
+    }
+
+    fn repair_image(&self, _image: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    fn create_image(&self, _image: &Path, _size: u64) -> Result<()> {
+        Ok(())
+    }
+
+    fn pack_image(&self, _source: &Path, _image: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    fn mount_packed_image(&self, image: &Path, target: &Path) -> Result<()> {
+        self.attach(SimFs::Image, target, vec![image.to_path_buf()])
+    }
+
+    fn relabel_tree(&self, _path: &Path, _reference: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    fn unmount(&self, target: &Path) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+
+        let Some(pos) = state.mounts.iter().rposition(|m| m.target == target) else {
+            bail!("{} is not mounted", target.display());
+        };
+
+        state.mounts.remove(pos);
+
+        state.views.retain(|path, _| !path.starts_with(target));
+
+        Ok(())
+    }
+
+    fn bind_readonly(&self, source: &Path, target: &Path) -> Result<()> {
+        self.attach(SimFs::Bind, target, vec![source.to_path_buf()])?;
+
+        self.show(target.to_path_buf(), source.to_path_buf());
+
+        Ok(())
+    }
+
+    fn mount_overlay(
+        &self,
+        target: &str,
+        lowerdirs: &[String],
+        _workdir: Option<PathBuf>,
+        _upperdir: Option<PathBuf>,
+        _disable_umount: bool,
+    ) -> Result<()> {
+        let target = Path::new(target);
+
+        let sources: Vec<PathBuf> = lowerdirs.iter().map(PathBuf::from).collect();
+
+        if self.check_layers(target, lowerdirs).is_err()
+            || self
+                .attach(SimFs::Overlay, target, sources.clone())
+                .is_err()
+        {
+            bail!(HybridError::OverlayMount {
+                target: target.to_path_buf(),
+            });
+        }
+
+        // The first lowerdir has the highest precedence, so lay them down last.
+        for lower in sources.iter().rev() {
+            for entry in WalkDir::new(lower).min_depth(1).into_iter().flatten() {
+                if entry.file_type().is_dir() {
+                    continue;
+                }
+
+                if let Ok(relative) = entry.path().strip_prefix(lower) {
+                    self.show(target.join(relative), entry.path().to_path_buf());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn probe_overlay(&self, target: &str, lowerdirs: &[String]) -> Result<()> {
+        self.check_layers(Path::new(target), lowerdirs)
+    }
+
+    fn magic_mount(&self, request: &MagicRequest) -> Result<()> {
+        let Some(root) = magic::build_tree(request)? else {
+            return Ok(());
+        };
+
+        self.attach(SimFs::Magic, request.sysroot, request.module_paths.to_vec())?;
+
+        self.project(&root, request.sysroot);
+
+        Ok(())
+    }
+
+    fn temp_dir(&self) -> Result<PathBuf> {
+        Ok(self.temp_root.clone())
+    }
+
+    fn checkpoint(&self) -> usize {
+        self.state.lock().unwrap().mounts.len()
+    }
+
+    fn rollback(&self, checkpoint: usize) -> TeardownReport {
+        let mut state = self.state.lock().unwrap();
+
+        let at = checkpoint.min(state.mounts.len());
+
+        let undone = state.mounts.split_off(at);
+
+        let mut report = TeardownReport::default();
+
+        for mount in undone.into_iter().rev() {
+            state
+                .views
+                .retain(|path, _| !path.starts_with(&mount.target));
+
+            report.detached.push(mount.target);
+        }
+
+        report
+    }
+}
@@ -6,7 +6,7 @@ use std::{
     fmt as std_fmt,
     fs::{self, File, create_dir_all, remove_dir_all, remove_file, write},
     io::Write,
-    os::unix::fs::{PermissionsExt, symlink},
+    os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt, lchown, symlink},
     path::{Path, PathBuf},
     process::{Command, Stdio},
     sync::OnceLock,
@@ -17,7 +17,7 @@ use anyhow::{Context, Result, bail};
 use procfs::process::Process;
 use regex_lite::Regex;
 use rustix::{
-    fs::ioctl_ficlone,
+    fs::{AtFlags, CWD, FileType, Mode, Timespec, Timestamps, ioctl_ficlone, mknodat, utimensat},
     mount::{MountFlags, mount},
 };
 use tracing::{Event, Subscriber};
@@ -30,7 +30,10 @@ use tracing_subscriber::{
     util::SubscriberInitExt,
 };
 
-use crate::defs::{self, TMPFS_CANDIDATES};
+use crate::{
+    defs::{self, TMPFS_CANDIDATES},
+    error::HybridError,
+};
 
 #[cfg(any(target_os = "linux", target_os = "android"))]
 use extattr::{Flags as XattrFlags, lsetxattr};
@@ -152,12 +155,21 @@ pub fn lsetfilecon<P: AsRef<Path>>(path: P, con: &str) -> Result<()> {
         if let Err(e) = lsetxattr(&path, SELINUX_XATTR, con, XattrFlags::empty()) {
             let io_err = std::io::Error::from(e);
 
-            log::debug!(
-                "lsetfilecon: {} -> {} failed: {}",
-                path.as_ref().display(),
-                con,
-                io_err
-            );
+            // Without SELinux there is nothing to relabel.
+            if io_err.raw_os_error() == Some(libc::EOPNOTSUPP) {
+                log::debug!(
+                    "lsetfilecon: {} -> {} skipped: {}",
+                    path.as_ref().display(),
+                    con,
+                    io_err
+                );
+
+                return Ok(());
+            }
+
+            return Err(io_err).context(HybridError::SelinuxContext {
+                path: path.as_ref().to_path_buf(),
+            });
         }
     }
 
@@ -171,13 +183,23 @@ pub fn lsetfilecon<P: AsRef<Path>>(path: P, con: &str) -> Result<()> {
     Ok(())
 }
 
+// Most labels are applied on a best effort basis: a denial is logged and the
+// caller carries on with whatever label the file already has.
+pub fn lsetfilecon_lossy<P: AsRef<Path>>(path: P, con: &str) {
+    if let Err(e) = lsetfilecon(&path, con) {
+        log::debug!(
+            "lsetfilecon: {} -> {} failed: {:#}",
+            path.as_ref().display(),
+            con,
+            e
+        );
+    }
+}
+
 #[cfg(any(target_os = "linux", target_os = "android"))]
 pub fn lgetfilecon<P: AsRef<Path>>(path: P) -> Result<String> {
-    let con = extattr::lgetxattr(&path, SELINUX_XATTR).with_context(|| {
-        format!(
-            "Failed to get SELinux context for {}",
-            path.as_ref().display()
-        )
+    let con = extattr::lgetxattr(&path, SELINUX_XATTR).context(HybridError::SelinuxRead {
+        path: path.as_ref().to_path_buf(),
     })?;
 
     Ok(String::from_utf8_lossy(&con).to_string())
@@ -198,6 +220,11 @@ pub fn copy_path_context<S: AsRef<Path>, D: AsRef<Path>>(src: S, dst: D) -> Resu
     lsetfilecon(dst, &context)
 }
 
+// Joins an absolute system path onto the configured root instead of replacing it.
+pub fn under_root(sysroot: &Path, path: &Path) -> PathBuf {
+    sysroot.join(path.strip_prefix("/").unwrap_or(path))
+}
+
 pub fn ensure_dir_exists<T: AsRef<Path>>(dir: T) -> Result<()> {
     if !dir.as_ref().exists() {
         create_dir_all(&dir)?;
@@ -358,7 +385,10 @@ pub fn mount_image(image_path: &Path, target: &Path) -> Result<()> {
         .context("Failed to execute mount command")?;
 
     if !status.success() {
-        bail!("Mount command failed");
+        bail!(HybridError::StorageMount {
+            target: target.to_path_buf(),
+            fstype: "ext4",
+        });
     }
 
     Ok(())
@@ -382,6 +412,63 @@ pub fn repair_image(image_path: &Path) -> Result<()> {
     Ok(())
 }
 
+pub fn create_ext4_image(image_path: &Path, size: u64) -> Result<()> {
+    let status = Command::new("truncate")
+        .arg("-s")
+        .arg(size.to_string())
+        .arg(image_path)
+        .status()?;
+
+    if !status.success() {
+        bail!("Failed to allocate image file");
+    }
+
+    let status = Command::new("mkfs.ext4")
+        .arg("-O")
+        .arg("^has_journal")
+        .arg(image_path)
+        .status()?;
+
+    if !status.success() {
+        bail!("Failed to format image file");
+    }
+
+    Ok(())
+}
+
+// Copies the labels of `reference` onto the tree at `path`, falling back to the
+// stock system label when the reference cannot be used.
+pub fn relabel_tree(path: &Path, reference: &Path) -> Result<()> {
+    let status = Command::new("chcon")
+        .arg("-R")
+        .arg("--reference")
+        .arg(reference)
+        .arg(path)
+        .status();
+
+    if status.is_ok_and(|s| s.success()) {
+        return Ok(());
+    }
+
+    log::warn!(
+        "chcon --reference failed, trying explicit context {}",
+        DEFAULT_CONTEXT
+    );
+
+    let status = Command::new("chcon")
+        .arg("-R")
+        .arg(DEFAULT_CONTEXT)
+        .arg(path)
+        .status()
+        .context("Failed to execute chcon")?;
+
+    if !status.success() {
+        bail!("chcon failed on {}", path.display());
+    }
+
+    Ok(())
+}
+
 pub fn reflink_or_copy(src: &Path, dest: &Path) -> Result<u64> {
     let src_file = File::open(src)?;
 
@@ -408,62 +495,165 @@ pub fn reflink_or_copy(src: &Path, dest: &Path) -> Result<u64> {
     fs::copy(src, dest).map_err(|e| e.into())
 }
 
-fn native_cp_r(src: &Path, dst: &Path) -> Result<()> {
-    if !dst.exists() {
-        create_dir_all(dst)?;
-
-        let src_meta = src.metadata()?;
+// Creates a directory carrying the owner, mode and xattrs of its source.
+pub fn create_dir_from(src: &Path, dst: &Path) -> Result<()> {
+    create_dir_all(dst)?;
 
-        fs::set_permissions(dst, src_meta.permissions())?;
+    copy_metadata(src, dst)
+}
 
-        lsetfilecon(dst, DEFAULT_CONTEXT)?;
-    }
+// Copies a single non-directory entry, replacing whatever is at `dst`.
+pub fn copy_entry(src: &Path, dst: &Path) -> Result<()> {
+    let metadata = src.symlink_metadata()?;
 
-    for entry in fs::read_dir(src)? {
-        let entry = entry?;
+    if is_whiteout(&metadata) {
+        // Overlayfs reads a 0:0 character device in a lower layer as a deletion.
+        mknodat(CWD, dst, FileType::CharacterDevice, Mode::empty(), 0)
+            .with_context(|| format!("failed to create whiteout {}", dst.display()))?;
+    } else if metadata.is_symlink() {
+        let link_target = fs::read_link(src)?;
 
-        let ft = entry.file_type()?;
+        if dst.symlink_metadata().is_ok() {
+            remove_file(dst)?;
+        }
 
-        let src_path = entry.path();
+        symlink(&link_target, dst)?;
+    } else if !metadata.is_file() {
+        // FIFOs, sockets and device nodes have no content to copy.
+        if dst.symlink_metadata().is_ok() {
+            remove_file(dst)?;
+        }
 
-        let dst_path = dst.join(entry.file_name());
+        if let Err(e) = mknodat(
+            CWD,
+            dst,
+            FileType::from_raw_mode(metadata.mode()),
+            Mode::from_raw_mode(metadata.mode()),
+            metadata.rdev(),
+        ) {
+            log::warn!("Skip special file {}: {}", src.display(), e);
 
-        if ft.is_dir() {
-            native_cp_r(&src_path, &dst_path)?;
-        } else if ft.is_symlink() {
-            let link_target = fs::read_link(&src_path)?;
+            return Ok(());
+        }
+    } else {
+        reflink_or_copy(src, dst)?;
+    }
 
-            if dst_path.exists() {
-                remove_file(&dst_path)?;
-            }
+    copy_metadata(src, dst)
+}
 
-            symlink(&link_target, &dst_path)?;
+pub fn is_whiteout(metadata: &fs::Metadata) -> bool {
+    metadata.file_type().is_char_device() && metadata.rdev() == 0
+}
 
-            let _ = lsetfilecon(&dst_path, DEFAULT_CONTEXT);
+pub fn set_overlay_opaque(dir: &Path, opaque: bool) -> Result<()> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        let result = if opaque {
+            lsetxattr(dir, defs::REPLACE_DIR_XATTR, "y", XattrFlags::empty())
+                .map_err(std::io::Error::from)
         } else {
-            reflink_or_copy(&src_path, &dst_path)?;
+            match extattr::lremovexattr(dir, defs::REPLACE_DIR_XATTR).map_err(std::io::Error::from)
+            {
+                Err(e) if e.raw_os_error() == Some(libc::ENODATA) => Ok(()),
+                other => other,
+            }
+        };
 
-            lsetfilecon(&dst_path, DEFAULT_CONTEXT)?;
-        }
+        result.with_context(|| format!("failed to update opaque flag on {}", dir.display()))?;
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    {
+        let _ = (dir, opaque);
     }
 
     Ok(())
 }
 
-pub fn sync_dir(src: &Path, dst: &Path) -> Result<()> {
-    if !src.exists() {
-        return Ok(());
+pub fn is_overlay_opaque(dir: &Path) -> bool {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        extattr::lgetxattr(dir, defs::REPLACE_DIR_XATTR).is_ok_and(|value| value == b"y")
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    {
+        let _ = dir;
+
+        false
+    }
+}
+
+// chown clears setuid bits and security.capability, so ownership goes first
+// and the timestamps last.
+pub fn copy_metadata(src: &Path, dst: &Path) -> Result<()> {
+    let metadata = src.symlink_metadata()?;
+
+    lchown(dst, Some(metadata.uid()), Some(metadata.gid()))
+        .with_context(|| format!("failed to chown {}", dst.display()))?;
+
+    if !metadata.file_type().is_symlink() {
+        fs::set_permissions(dst, metadata.permissions())?;
     }
 
-    ensure_dir_exists(dst)?;
+    copy_xattrs(src, dst)?;
 
-    native_cp_r(src, dst).with_context(|| {
-        format!(
-            "Failed to natively sync {} to {}",
-            src.display(),
-            dst.display()
-        )
-    })
+    copy_times(src, dst)
+}
+
+pub fn copy_times(src: &Path, dst: &Path) -> Result<()> {
+    let metadata = src.symlink_metadata()?;
+
+    let times = Timestamps {
+        last_access: Timespec {
+            tv_sec: metadata.atime(),
+            tv_nsec: metadata.atime_nsec(),
+        },
+        last_modification: Timespec {
+            tv_sec: metadata.mtime(),
+            tv_nsec: metadata.mtime_nsec(),
+        },
+    };
+
+    utimensat(CWD, dst, &times, AtFlags::SYMLINK_NOFOLLOW)
+        .with_context(|| format!("failed to set timestamps on {}", dst.display()))
+}
+
+// Entries without a label of their own get the default system context.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn copy_xattrs(src: &Path, dst: &Path) -> Result<()> {
+    let mut labelled = false;
+
+    for name in extattr::llistxattr(src).unwrap_or_default() {
+        let Ok(value) = extattr::lgetxattr(src, &name) else {
+            continue;
+        };
+
+        if let Err(e) = lsetxattr(dst, &name, &value, XattrFlags::empty()) {
+            log::warn!(
+                "Failed to copy {} to {}: {}",
+                name.to_string_lossy(),
+                dst.display(),
+                std::io::Error::from(e)
+            );
+
+            continue;
+        }
+
+        labelled |= name == SELINUX_XATTR;
+    }
+
+    if !labelled {
+        lsetfilecon_lossy(dst, DEFAULT_CONTEXT);
+    }
+
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn copy_xattrs(_src: &Path, _dst: &Path) -> Result<()> {
+    Ok(())
 }
 
 fn is_ok_empty<P: AsRef<Path>>(dir: P) -> bool {
@@ -543,7 +733,7 @@ pub fn create_erofs_image(src_dir: &Path, image_path: &Path) -> Result<()> {
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
         .output()
-        .context("Failed to execute mkfs.erofs")?;
+        .context(HybridError::ErofsSpawn)?;
 
     let log_lines = |bytes: &[u8]| {
         let s = String::from_utf8_lossy(bytes);
@@ -560,14 +750,14 @@ pub fn create_erofs_image(src_dir: &Path, image_path: &Path) -> Result<()> {
     log_lines(&output.stderr);
 
     if !output.status.success() {
-        bail!("Failed to create EROFS image");
+        bail!(HybridError::ErofsPack(image_path.to_path_buf()));
     }
 
     log::info!("Build Completed.");
 
     let _ = fs::set_permissions(image_path, fs::Permissions::from_mode(0o644));
 
-    lsetfilecon(image_path, "u:object_r:ksu_file:s0")?;
+    lsetfilecon_lossy(image_path, "u:object_r:ksu_file:s0");
 
     Ok(())
 }
@@ -585,7 +775,10 @@ pub fn mount_erofs_image(image_path: &Path, target: &Path) -> Result<()> {
         .context("Failed to execute mount command for EROFS")?;
 
     if !status.success() {
-        bail!("EROFS Mount command failed");
+        bail!(HybridError::StorageMount {
+            target: target.to_path_buf(),
+            fstype: "erofs",
+        });
     }
 
     Ok(())
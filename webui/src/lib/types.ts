@@ -20,6 +20,7 @@ export interface AppConfig {
   allow_umount_coexistence: boolean;
   dry_run: boolean;
   logfile?: string;
+  priority?: string[];
   winnowing?: Record<string, string>;
   granary: GranaryConfig;
 }
@@ -39,6 +40,9 @@ export interface Module {
   description: string;
   mode: string;
   is_mounted: boolean;
+  overlay_partitions?: string[];
+  is_magic?: boolean;
+  fallback_error?: string | null;
   rules: ModuleRules;
   enabled?: boolean;
   source_path?: string;